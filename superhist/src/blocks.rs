@@ -0,0 +1,138 @@
+//! Seekable archive format: a sequence of independently xz-compressed blocks
+//! of history lines, followed by a JSON block index and a fixed trailer:
+//!
+//!     [block 0] [block 1] ... [index JSON] [index offset: u64 LE] [MAGIC]
+//!
+//! As with plain `.xz` archives, lines are stored newest first. In encrypted
+//! archives, each compressed block and the index JSON are sealed, and the
+//! trailer ends with MAGIC_SEALED instead.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+use crate::crypt::Cipher;
+use crate::index::SegmentIndex;
+use crate::{Error, Event};
+
+const MAGIC: &[u8; 8] = b"SHBLOCK1";
+const MAGIC_SEALED: &[u8; 8] = b"SHBLKENC";
+
+/// Number of history lines per compressed block
+const BLOCK_LINES: usize = 2048;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BlockIndex {
+    /// Byte range of the compressed block in the file
+    pub offset: u64,
+    pub len: u64,
+
+    /// Range of lines in the segment held by this block
+    pub first_line: u64,
+    pub lines: u64,
+
+    pub summary: SegmentIndex,
+
+    /// Whether the block is encrypted
+    #[serde(default)]
+    pub sealed: bool,
+}
+
+/// Write `lines` (newest first) as a block archive. `events` holds the parsed
+/// form of each line, or None for lines that did not parse. With a cipher, the
+/// archive is encrypted.
+pub fn write(path: &Path, lines: &[String], events: &[Option<Event>], cipher: Option<&Cipher>) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut offset = 0;
+    let mut blocks = vec![];
+
+    for (nr, chunk) in lines.chunks(BLOCK_LINES).enumerate() {
+        let first_line = nr * BLOCK_LINES;
+        let mut compressor = XzEncoder::new(vec![], 9);
+        for line in chunk {
+            compressor.write_all(line.as_bytes())?;
+            compressor.write_all("\n".as_bytes())?;
+        }
+        let mut compressed = compressor.finish()?;
+        if let Some(cipher) = cipher {
+            compressed = cipher.seal(&compressed);
+        }
+        writer.write_all(&compressed)?;
+
+        let block_events = &events[first_line .. first_line + chunk.len()];
+        blocks.push(BlockIndex {
+            offset,
+            len: compressed.len() as u64,
+            first_line: first_line as u64,
+            lines: chunk.len() as u64,
+            summary: SegmentIndex::from_events_rev(block_events.iter().flatten()),
+            sealed: cipher.is_some(),
+        });
+        offset += compressed.len() as u64;
+    }
+
+    let json = serde_json::to_vec(&blocks)?;
+    match cipher {
+        Some(cipher) => writer.write_all(&cipher.seal(&json))?,
+        None => writer.write_all(&json)?,
+    }
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.write_all(if cipher.is_some() { MAGIC_SEALED } else { MAGIC })?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read the block index from the trailer of a block archive
+pub fn read_index(file: &mut File, cipher: Option<&Cipher>) -> Result<Vec<BlockIndex>, Error> {
+    let end = file.seek(SeekFrom::End(-16))?;
+    let mut trailer = [0u8; 16];
+    file.read_exact(&mut trailer)?;
+    let sealed = if &trailer[8..] == MAGIC {
+        false
+    } else if &trailer[8..] == MAGIC_SEALED {
+        true
+    } else {
+        return Err(Error::BadArchive);
+    };
+
+    let mut offset_bytes = [0u8; 8];
+    offset_bytes.copy_from_slice(&trailer[..8]);
+    let offset = u64::from_le_bytes(offset_bytes);
+    if offset > end {
+        return Err(Error::BadArchive);
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut json = vec![0u8; (end - offset) as usize];
+    file.read_exact(&mut json)?;
+    if sealed {
+        json = cipher.ok_or(Error::NoKey)?.open(&json)?;
+    }
+
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Decompress a single block
+pub fn read_block(file: &mut File, block: &BlockIndex, cipher: Option<&Cipher>) -> Result<Vec<u8>, Error> {
+    file.seek(SeekFrom::Start(block.offset))?;
+
+    let mut data = vec![];
+    if block.sealed {
+        let mut sealed = vec![];
+        Read::by_ref(file).take(block.len).read_to_end(&mut sealed)?;
+        let compressed = cipher.ok_or(Error::NoKey)?.open(&sealed)?;
+        XzDecoder::new(compressed.as_slice()).read_to_end(&mut data)?;
+    } else {
+        XzDecoder::new(Read::by_ref(file).take(block.len)).read_to_end(&mut data)?;
+    }
+    if data.last() == Some(&b'\n') {
+        data.pop();
+    }
+
+    Ok(data)
+}
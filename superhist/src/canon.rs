@@ -0,0 +1,76 @@
+//! Resolving symlinks in the workdirs of commands recorded before the
+//! physical workdir was stored along with the logical one.
+
+use std::collections::HashMap;
+
+use crate::filter::physical_dir;
+use crate::{Error, Event, Payload, SuperHist};
+
+impl SuperHist {
+    /// Store the physical workdir of the commands in db.json and all
+    /// archives that lack one. With `dry_run`, only list the commands that
+    /// would change.
+    pub(crate) fn recanonicalize(&self, dry_run: bool) -> Result<(), Error> {
+        let lock = self.lock()?;
+        self.recover_main_db()?;
+
+        let mut resolved : HashMap<String, Option<String>> = HashMap::new();
+
+        for segment in self.segments()? {
+            let path = segment.path(self);
+            let (lines, damages) = self.segment_lines(&segment)?;
+            if !damages.is_empty() {
+                eprintln!("superhist: warning - {}: not recanonicalizing a damaged segment, run `superhist fsck` first",
+                    path.display());
+                continue;
+            }
+
+            let mut changed = 0;
+            let mut new_lines = Vec::with_capacity(lines.len());
+            for line in lines.into_iter() {
+                let mut event : Event = match serde_json::de::from_str(&line) {
+                    Ok(event) => event,
+                    Err(_) => {
+                        new_lines.push(line);
+                        continue;
+                    }
+                };
+
+                let physical = match &mut event.payload {
+                    Payload::Command { text, workdir, physical_workdir: physical_workdir @ None, .. } => {
+                        let physical = resolved.entry(workdir.clone())
+                            .or_insert_with(|| physical_dir(workdir));
+                        match physical {
+                            Some(physical) => {
+                                if dry_run {
+                                    println!("{}: {} {} -> {}", path.display(), event.timestamp,
+                                        text.replace("\n", "\\n"), physical);
+                                }
+                                *physical_workdir = Some(physical.clone());
+                                true
+                            }
+                            None => false,
+                        }
+                    }
+                    _ => false,
+                };
+
+                if !physical {
+                    new_lines.push(line);
+                    continue;
+                }
+
+                changed += 1;
+                new_lines.push(serde_json::ser::to_string(&event)?);
+            }
+
+            if changed > 0 && !dry_run {
+                self.write_segment_lines(&segment, &new_lines)?;
+                println!("{}: recanonicalized {} commands", path.display(), changed);
+            }
+        }
+
+        lock.unlock()?;
+        Ok(())
+    }
+}
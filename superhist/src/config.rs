@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use serde::{Serialize, Deserialize};
+
+use crate::index::SegmentIndex;
+use crate::{Error, UnixTime};
+
+/// When appends to db.json are forced to disk
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    /// Leave it to the OS
+    None,
+
+    /// fsync after every batch of events
+    Batch,
+
+    /// fsync after a batch if the last fsync is older than `sync_interval`
+    Periodic,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::None
+    }
+}
+
+/// When `add` archives db.json by itself. Each limit is optional, and
+/// reaching any of them triggers archiving.
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct Rotation {
+    /// Number of events in db.json
+    pub max_events: Option<u64>,
+
+    /// Size of db.json
+    pub max_bytes: Option<u64>,
+
+    /// Seconds since the oldest event in db.json
+    pub max_age: Option<u64>,
+}
+
+impl Rotation {
+    pub fn is_due(&self, index: &SegmentIndex, now: UnixTime) -> bool {
+        self.max_events.map_or(false, |max| index.events >= max) ||
+            self.max_bytes.map_or(false, |max| index.source_len >= max) ||
+            self.max_age.map_or(false, |max| {
+                index.min_timestamp.map_or(false, |oldest| now.saturating_sub(oldest) >= max)
+            })
+    }
+}
+
+/// What `add` removes from command lines before they are written. Nothing
+/// unless configured, as redacted text cannot be recovered.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Redaction {
+    /// Look for common shapes of tokens, keys and passwords
+    pub builtin: bool,
+
+    /// Additional regexes. Where a regex has a `secret` group, only that
+    /// part of the match is replaced.
+    pub patterns: Vec<String>,
+
+    /// Text that replaces each secret
+    pub placeholder: String,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Redaction {
+            builtin: false,
+            patterns: vec![],
+            placeholder: "[REDACTED]".to_owned(),
+        }
+    }
+}
+
+/// Commands that `add` never writes
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Ignore {
+    /// Globs on the command text
+    pub commands: Vec<String>,
+
+    /// Regexes on the command text
+    pub regexes: Vec<String>,
+
+    /// Globs on the workdir, where `*` does not match `/` and `**` does
+    pub workdirs: Vec<String>,
+
+    /// Commands starting with a space, as with zsh's HIST_IGNORE_SPACE
+    pub leading_space: bool,
+}
+
+impl Default for Ignore {
+    fn default() -> Self {
+        Ignore {
+            commands: vec![],
+            regexes: vec![],
+            workdirs: vec![],
+            leading_space: true,
+        }
+    }
+}
+
+/// Encryption at rest of new records and archives
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct Encryption {
+    /// Key file, relative to the root. Created by `superhist keygen`.
+    pub key_file: Option<PathBuf>,
+}
+
+/// Per-root settings, read from config.yaml under the root. All of them are
+/// optional.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub durability: Durability,
+
+    /// Seconds, for `Durability::Periodic`
+    pub sync_interval: u64,
+
+    pub rotation: Rotation,
+
+    pub redaction: Redaction,
+
+    pub ignore: Ignore,
+
+    pub encryption: Encryption,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            durability: Default::default(),
+            sync_interval: 60,
+            rotation: Default::default(),
+            redaction: Default::default(),
+            ignore: Default::default(),
+            encryption: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &PathBuf) -> Result<Self, Error> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_yaml::from_reader(BufReader::new(file))?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
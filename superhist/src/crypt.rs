@@ -0,0 +1,125 @@
+//! Optional encryption at rest, with XChaCha20-Poly1305 under a key kept in
+//! a local file.
+//!
+//! Records in db.json and in plain or `.xz` archives are sealed one line at
+//! a time, and stored as the line prefix followed by the base64 of the nonce
+//! and the ciphertext:
+//!
+//!     !1:<base64>
+//!
+//! Block archives have each compressed block sealed as a whole, and so do the
+//! index and trigram caches of encrypted segments. Records that are not
+//! sealed are read as before, so a history can hold both.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use crate::{Error, SuperHist};
+
+const LINE_PREFIX: &str = "!1:";
+
+const NONCE_LEN: usize = 24;
+
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+pub fn is_sealed_line(line: &str) -> bool {
+    line.starts_with(LINE_PREFIX)
+}
+
+impl Cipher {
+    /// Read a key file, holding the base64 of 32 bytes
+    pub fn load(path: &PathBuf) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)?;
+        let key = base64::decode(text.trim()).map_err(|_| Error::BadKey(path.clone()))?;
+        if key.len() != 32 {
+            return Err(Error::BadKey(path.clone()));
+        }
+
+        Ok(Cipher { aead: XChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        // Encryption with a valid key and nonce cannot fail
+        sealed.extend(self.aead.encrypt(&nonce, data).expect("encryption"));
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::Decryption);
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+        self.aead.decrypt(XNonce::from_slice(nonce), data).map_err(|_| Error::Decryption)
+    }
+
+    pub fn seal_line(&self, line: &str) -> String {
+        format!("{}{}", LINE_PREFIX, base64::encode(self.seal(line.as_bytes())))
+    }
+
+    pub fn open_line(&self, line: &str) -> Result<String, Error> {
+        let sealed = base64::decode(&line[LINE_PREFIX.len() ..]).map_err(|_| Error::Decryption)?;
+        String::from_utf8(self.open(&sealed)?).map_err(|_| Error::Decryption)
+    }
+}
+
+/// Open a line as stored, which may or may not be sealed
+pub fn open_stored_line(cipher: Option<&Cipher>, line: String) -> Result<String, Error> {
+    if !is_sealed_line(&line) {
+        return Ok(line);
+    }
+
+    match cipher {
+        Some(cipher) => cipher.open_line(&line),
+        None => Err(Error::NoKey),
+    }
+}
+
+/// Prepare a line for storage, sealed if there is a cipher
+pub fn seal_stored_line(cipher: Option<&Cipher>, line: &str) -> String {
+    match cipher {
+        Some(cipher) => cipher.seal_line(line),
+        None => line.to_owned(),
+    }
+}
+
+impl SuperHist {
+    fn key_file(&self) -> Option<PathBuf> {
+        self.config.encryption.key_file.as_ref().map(|path| self.root.join(path))
+    }
+
+    /// The cipher for new records, if encryption is configured. The key file
+    /// is read once per run.
+    pub(crate) fn cipher(&self) -> Result<Option<&Cipher>, Error> {
+        if let Some(cipher) = self.cipher.get() {
+            return Ok(cipher.as_ref());
+        }
+
+        let cipher = match self.key_file() {
+            Some(path) => Some(Cipher::load(&path)?),
+            None => None,
+        };
+        Ok(self.cipher.get_or_init(|| cipher).as_ref())
+    }
+
+    /// Create the configured key file with a fresh key
+    pub(crate) fn keygen(&self) -> Result<(), Error> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = self.key_file().ok_or(Error::InvalidParams)?;
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true).create_new(true).mode(0o600)
+            .open(&path)?;
+        writeln!(file, "{}", base64::encode(key))?;
+        println!("{}: created", path.display());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use filetime::FileTime;
+
+use crate::config::Durability;
+use crate::{Error, SuperHist};
+
+impl SuperHist {
+    /// Holds "<db.json length before> <batch length>" while a batch is being
+    /// appended, so that a batch cut short by a crash can be undone.
+    fn journal_file(&self) -> PathBuf {
+        self.root.join("db.json.journal")
+    }
+
+    /// Touched on every fsync of db.json, for `Durability::Periodic`
+    fn sync_stamp_file(&self) -> PathBuf {
+        self.root.join("db.json.synced")
+    }
+
+    /// Undo the effects of an interrupted append to db.json. Must be called
+    /// under the lock.
+    pub(crate) fn recover_main_db(&self) -> Result<(), Error> {
+        let db = self.main_db_file();
+        let len = std::fs::metadata(&db).map(|m| m.len()).unwrap_or(0);
+
+        if let Ok(journal) = std::fs::read_to_string(self.journal_file()) {
+            let mut parts = journal.split_whitespace().map(|s| s.parse::<u64>());
+            if let (Some(Ok(prev_len)), Some(Ok(batch_len))) = (parts.next(), parts.next()) {
+                if len > prev_len && len != prev_len + batch_len {
+                    eprintln!("superhist: warning - dropping {} bytes of an incomplete append", len - prev_len);
+                    OpenOptions::new().write(true).open(&db)?.set_len(prev_len)?;
+                }
+            }
+            std::fs::remove_file(self.journal_file())?;
+        }
+
+        // A record torn by a writer that did not keep a journal
+        let len = std::fs::metadata(&db).map(|m| m.len()).unwrap_or(0);
+        if len > 0 {
+            let mut file = OpenOptions::new().read(true).write(true).open(&db)?;
+            let complete_len = complete_lines_len(&mut file, len)?;
+            if complete_len < len {
+                let mut torn = vec![];
+                file.seek(SeekFrom::Start(complete_len))?;
+                file.read_to_end(&mut torn)?;
+                File::create(self.quarantine_file("db.json.torn")?)?.write_all(&torn)?;
+                eprintln!("superhist: warning - dropping a torn record of {} bytes", torn.len());
+                file.set_len(complete_len)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Append `data` to db.json as a whole, and make it durable according to
+    /// the configured policy. Must be called under the lock, after
+    /// `recover_main_db`.
+    pub(crate) fn append_main_db(&self, prev_len: u64, data: &[u8]) -> Result<(), Error> {
+        let durability = self.config.durability;
+
+        let mut journal = File::create(self.journal_file())?;
+        writeln!(journal, "{} {}", prev_len, data.len())?;
+        if durability == Durability::Batch {
+            journal.sync_all()?;
+        }
+        drop(journal);
+
+        let mut file = OpenOptions::new().create(true).append(true).open(self.main_db_file())?;
+
+        file.write_all(data)?;
+
+        match durability {
+            Durability::None => {}
+            Durability::Batch => file.sync_data()?,
+            Durability::Periodic => {
+                let stamp = self.sync_stamp_file();
+                let last_sync = std::fs::metadata(&stamp).ok()
+                    .map(|m| FileTime::from_last_modification_time(&m).unix_seconds())
+                    .unwrap_or(0);
+                let now = FileTime::now().unix_seconds();
+                if now - last_sync >= self.config.sync_interval as i64 {
+                    file.sync_data()?;
+                    File::create(&stamp)?;
+                    filetime::set_file_mtime(&stamp, FileTime::now())?;
+                }
+            }
+        }
+
+        std::fs::remove_file(self.journal_file())?;
+
+        Ok(())
+    }
+}
+
+/// Length of the file up to and including its last newline
+fn complete_lines_len(file: &mut File, len: u64) -> Result<u64, Error> {
+    let mut end = len;
+    let mut buf = vec![0u8; 0x10000];
+
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(pos) = chunk.iter().rposition(|b| *b == b'\n') {
+            return Ok(start + pos as u64 + 1);
+        }
+        end = start;
+    }
+
+    Ok(0)
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::filter::{run_time, EventFilter, ExitFilter};
+use crate::{Error, Event, Payload, SuperHist, UnixTime};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    /// zsh EXTENDED_HISTORY, `: start:elapsed;command`
+    Zsh,
+    /// bash HISTTIMEFORMAT history, with `#timestamp` lines
+    Bash,
+    /// fish history YAML
+    Fish,
+    /// A JSON object per command
+    Jsonl,
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "zsh" => Ok(ExportFormat::Zsh),
+            "bash" => Ok(ExportFormat::Bash),
+            "fish" => Ok(ExportFormat::Fish),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!("unknown format {:?}", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExportRecord<'a> {
+    timestamp: UnixTime,
+    command: &'a str,
+    workdir: &'a str,
+    terminal: &'a str,
+    host: Option<&'a str>,
+    exit_code: Option<u32>,
+    duration: Option<u64>,
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace("\"", "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn write_record(writer: &mut dyn Write, format: ExportFormat, event: &Event,
+    exit: Option<(u32, UnixTime)>) -> Result<(), Error>
+{
+    let (text, workdir) = match &event.payload {
+        Payload::Command { text, workdir, .. } => (text, workdir),
+        _ => return Ok(()),
+    };
+    let duration = run_time(event, exit);
+
+    match format {
+        ExportFormat::Zsh => {
+            writeln!(writer, ": {}:{};{}", event.timestamp, duration.unwrap_or(0),
+                text.replace("\n", "\\\n"))?;
+        }
+        ExportFormat::Bash => {
+            writeln!(writer, "#{}\n{}", event.timestamp, text)?;
+        }
+        ExportFormat::Fish => {
+            writeln!(writer, "- cmd: {}\n  when: {}", text.replace("\\", "\\\\").replace("\n", "\\n"),
+                event.timestamp)?;
+        }
+        ExportFormat::Jsonl => {
+            let record = ExportRecord {
+                timestamp: event.timestamp,
+                command: text,
+                workdir,
+                terminal: &event.terminal,
+                host: event.host.as_deref(),
+                exit_code: exit.map(|(code, _)| code),
+                duration,
+            };
+            writeln!(writer, "{}", serde_json::ser::to_string(&record)?)?;
+        }
+        ExportFormat::Csv => {
+            let optional = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+            writeln!(writer, "{},{},{},{},{},{},{}", event.timestamp, csv_field(workdir),
+                csv_field(&event.terminal), csv_field(event.host.as_deref().unwrap_or("")),
+                optional(exit.map(|(code, _)| code as u64)), optional(duration), csv_field(text))?;
+        }
+    }
+
+    Ok(())
+}
+
+impl SuperHist {
+    /// Write the commands matching the filters, oldest first, in the format
+    /// of another shell's history or of a data file
+    pub(crate) fn export(&self, format: ExportFormat, filter: &EventFilter, exit_filter: &ExitFilter,
+        output: &Option<PathBuf>) -> Result<(), Error>
+    {
+        let mut commands = vec![];
+        self.scan_commands(None, |index| filter.may_match(index), |event, exit| {
+            if filter.matches(&event) && exit_filter.matches(&event, exit) {
+                commands.push((event, exit));
+            }
+            Ok(true)
+        })?;
+
+        let mut writer : BufWriter<Box<dyn Write>> = BufWriter::new(match output {
+            Some(path) => Box::new(File::create(path)?),
+            None => Box::new(std::io::stdout()),
+        });
+
+        if let ExportFormat::Csv = format {
+            writeln!(writer, "timestamp,workdir,terminal,host,exit_code,duration,command")?;
+        }
+        for (event, exit) in commands.iter().rev() {
+            write_record(&mut writer, format, event, *exit)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use std::str::FromStr;
+
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use structopt::StructOpt;
+
+use crate::index::SegmentIndex;
+use crate::merge::local_host;
+use crate::{parse_duration, Error, Event, Payload, UnixTime};
+
+/// Parse a point in time: seconds since the epoch, or a local date such as
+/// 2022-09-30 or a local date and time such as 2022-09-30T14:00:00
+pub fn parse_time(s: &str) -> Result<UnixTime, Error> {
+    use chrono::prelude::*;
+
+    if let Ok(secs) = s.parse() {
+        return Ok(secs);
+    }
+
+    let naive = match NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S") {
+        Ok(naive) => naive,
+        Err(_) => NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .map_err(|_| Error::InvalidParams)?
+            .and_hms_opt(0, 0, 0)
+            .ok_or(Error::InvalidParams)?,
+    };
+
+    match Local.from_local_datetime(&naive).earliest() {
+        Some(datetime) => Ok(datetime.timestamp() as UnixTime),
+        None => Err(Error::InvalidParams),
+    }
+}
+
+/// How a workdir criterion matches the workdirs of commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// The directory itself
+    Exact,
+    /// The directory and everything below it
+    Subtree,
+    /// All worktrees of the git repository holding the directory
+    Repo,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Scope::Exact
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "exact" => Ok(Scope::Exact),
+            "subtree" => Ok(Scope::Subtree),
+            "repo" => Ok(Scope::Repo),
+            _ => Err(format!("unknown scope {:?}", s)),
+        }
+    }
+}
+
+/// A directory with symlinks resolved, if that is not how it is given
+pub fn physical_dir(dir: &str) -> Option<String> {
+    let physical = std::fs::canonicalize(dir).ok()?.to_string_lossy().into_owned();
+    if physical != dir { Some(physical) } else { None }
+}
+
+/// Whether `dir` is `root` or below it
+pub fn is_under(root: &str, dir: &str) -> bool {
+    dir.starts_with(root) &&
+        (dir.len() == root.len() || root.ends_with('/') || dir[root.len() ..].starts_with('/'))
+}
+
+/// Top directories of all worktrees of the git repository holding `dir`
+fn git_worktrees(dir: &str) -> Option<Vec<String>> {
+    let output = std::process::Command::new("git")
+        .arg("-C").arg(dir)
+        .args(&["worktree", "list", "--porcelain"])
+        .stderr(std::process::Stdio::null())
+        .output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let worktrees : Vec<String> = String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| line.strip_prefix("worktree "))
+        .map(|path| path.to_owned())
+        .collect();
+    if worktrees.is_empty() { None } else { Some(worktrees) }
+}
+
+/// The directories whose subtrees a workdir covers in a scope, none of them
+/// below another, so that counts over them do not overlap. Outside of a git
+/// repository, the repo scope is the subtree of the workdir.
+fn scope_roots(workdir: &str, scope: Scope) -> Vec<String> {
+    let mut roots = match scope {
+        Scope::Exact | Scope::Subtree => vec![workdir.to_owned()],
+        Scope::Repo => git_worktrees(workdir).unwrap_or_else(|| vec![workdir.to_owned()]),
+    };
+
+    roots.sort();
+    let mut disjoint : Vec<String> = vec![];
+    for root in roots {
+        if !disjoint.iter().any(|outer| is_under(outer, &root)) {
+            disjoint.push(root);
+        }
+    }
+    disjoint
+}
+
+#[derive(StructOpt, Debug, Default)]
+pub struct FilterOpts {
+    /// Commands whose text matches this regex
+    #[structopt(long = "regex")]
+    pub regex: Option<String>,
+
+    /// Commands whose text matches this glob
+    #[structopt(long = "glob")]
+    pub glob: Option<String>,
+
+    /// Commands run in this directory
+    #[structopt(short = "w", long = "workdir")]
+    pub workdir: Option<String>,
+
+    /// What the workdir covers: exact, subtree, or repo for all worktrees
+    /// of its git repository
+    #[structopt(long = "scope", default_value = "exact")]
+    pub scope: Scope,
+
+    /// Events of this terminal
+    #[structopt(long = "terminal")]
+    pub terminal: Option<String>,
+
+    /// Events at or after this time
+    #[structopt(long = "since", parse(try_from_str = parse_time))]
+    pub since: Option<UnixTime>,
+
+    /// Events before this time
+    #[structopt(long = "until", parse(try_from_str = parse_time))]
+    pub until: Option<UnixTime>,
+
+    /// Events of this host, as named when merged. Events recorded here are
+    /// of the local host.
+    #[structopt(long = "host")]
+    pub host: Option<String>,
+}
+
+/// How long a command ran, from its exit or as recorded with it
+pub fn run_time(event: &Event, exit: Option<(u32, UnixTime)>) -> Option<u64> {
+    let recorded = match &event.payload {
+        Payload::Command { duration, .. } => *duration,
+        _ => None,
+    };
+    exit.map(|(_, timestamp)| timestamp.saturating_sub(event.timestamp)).or(recorded)
+}
+
+/// Selects commands by their exit status and how long they ran. Commands
+/// without a recorded exit status or run time match only when none is asked
+/// for.
+#[derive(StructOpt, Debug, Default)]
+pub struct ExitFilter {
+    /// Only commands that exited with this status
+    #[structopt(long = "exit-status")]
+    pub code: Option<u32>,
+
+    /// Only commands that exited with a non-zero status
+    #[structopt(long = "failed")]
+    pub failed: bool,
+
+    /// Only commands that exited with a zero status
+    #[structopt(long = "succeeded")]
+    pub succeeded: bool,
+
+    /// Only commands that ran at least this long (e.g. 90, 5m, 2h)
+    #[structopt(long = "min-duration", parse(try_from_str = parse_duration))]
+    pub min_duration: Option<u64>,
+
+    /// Only commands that ran at most this long
+    #[structopt(long = "max-duration", parse(try_from_str = parse_duration))]
+    pub max_duration: Option<u64>,
+}
+
+impl ExitFilter {
+    pub fn matches(&self, event: &Event, exit: Option<(u32, UnixTime)>) -> bool {
+        let code = exit.map(|(code, _)| code);
+        let status_matches = self.code.map_or(true, |wanted| code == Some(wanted)) &&
+            (!self.failed || code.map_or(false, |code| code != 0)) &&
+            (!self.succeeded || code == Some(0));
+
+        let duration_matches = match (self.min_duration, self.max_duration, run_time(event, exit)) {
+            (None, None, _) => true,
+            (min, max, Some(duration)) => {
+                min.map_or(true, |min| duration >= min) && max.map_or(true, |max| duration <= max)
+            }
+            (_, _, None) => false,
+        };
+
+        status_matches && duration_matches
+    }
+}
+
+/// Selects events by all of the given criteria. Criteria on command text and
+/// workdir never match events other than commands.
+pub struct EventFilter {
+    regex: Option<Regex>,
+    glob: Option<GlobMatcher>,
+    workdir: Option<String>,
+    /// For scopes other than exact, the directories covered with all below
+    roots: Option<Vec<String>>,
+    terminal: Option<String>,
+    since: Option<UnixTime>,
+    until: Option<UnixTime>,
+    host: Option<String>,
+    local_host: String,
+}
+
+impl EventFilter {
+    /// The workdir asked for is matched with symlinks resolved
+    pub fn new(opts: &FilterOpts) -> Result<Self, Error> {
+        let workdir = opts.workdir.as_ref()
+            .map(|workdir| physical_dir(workdir).unwrap_or_else(|| workdir.clone()));
+        Ok(EventFilter {
+            regex: match &opts.regex {
+                Some(regex) => Some(Regex::new(regex)?),
+                None => None,
+            },
+            glob: match &opts.glob {
+                Some(glob) => Some(Glob::new(glob)?.compile_matcher()),
+                None => None,
+            },
+            workdir: workdir.clone(),
+            roots: match (&workdir, opts.scope) {
+                (Some(_), Scope::Exact) | (None, _) => None,
+                (Some(workdir), scope) => Some(scope_roots(workdir, scope)),
+            },
+            terminal: opts.terminal.clone(),
+            since: opts.since,
+            until: opts.until,
+            host: opts.host.clone(),
+            local_host: match &opts.host {
+                Some(_) => local_host()?,
+                None => String::new(),
+            },
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regex.is_none() && self.glob.is_none() && self.workdir.is_none() &&
+            self.terminal.is_none() && self.since.is_none() && self.until.is_none() &&
+            self.host.is_none()
+    }
+
+    /// Whether a workdir is within the one asked for, in its scope
+    pub fn workdir_matches(&self, workdir: &str) -> bool {
+        match (&self.workdir, &self.roots) {
+            (None, _) => true,
+            (Some(_), Some(roots)) => roots.iter().any(|root| is_under(root, workdir)),
+            (Some(wanted), None) => wanted == workdir,
+        }
+    }
+
+    /// Number of commands in a segment or block that are within the
+    /// workdir asked for, judging by its summary
+    pub fn indexed_commands(&self, index: &SegmentIndex) -> u64 {
+        match &self.roots {
+            Some(roots) => roots.iter().map(|root| index.commands_under(root)).sum(),
+            None => index.commands(&self.workdir),
+        }
+    }
+
+    fn has_command_criteria(&self) -> bool {
+        self.regex.is_some() || self.glob.is_some() || self.workdir.is_some()
+    }
+
+    /// Whether a segment or block with this summary may hold matching
+    /// commands
+    pub fn may_match(&self, index: &SegmentIndex) -> bool {
+        if self.since.map_or(false, |since| index.max_timestamp.map_or(true, |t| t < since)) {
+            return false;
+        }
+        if self.until.map_or(false, |until| index.min_timestamp.map_or(true, |t| t >= until)) {
+            return false;
+        }
+        self.indexed_commands(index) > 0
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(terminal) = &self.terminal {
+            if &event.terminal != terminal {
+                return false;
+            }
+        }
+        if self.since.map_or(false, |since| event.timestamp < since) {
+            return false;
+        }
+        if self.until.map_or(false, |until| event.timestamp >= until) {
+            return false;
+        }
+        if let Some(host) = &self.host {
+            if event.host.as_ref().unwrap_or(&self.local_host) != host {
+                return false;
+            }
+        }
+
+        match &event.payload {
+            Payload::Command { text, .. } => {
+                if let Some(regex) = &self.regex {
+                    if !regex.is_match(text) {
+                        return false;
+                    }
+                }
+                if let Some(glob) = &self.glob {
+                    if !glob.is_match(text) {
+                        return false;
+                    }
+                }
+                if !self.workdir_matches(event.payload.physical_workdir().unwrap_or("")) {
+                    return false;
+                }
+                true
+            }
+            _ => !self.has_command_criteria(),
+        }
+    }
+}
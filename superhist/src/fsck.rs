@@ -0,0 +1,127 @@
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use crate::{Error, Event, Segment, SegmentItem, SuperHist};
+
+/// A part of a segment that could not be read
+#[derive(Debug)]
+pub struct Damage {
+    /// Line number in stored order, when the damage is known to be there
+    pub line: Option<u64>,
+    pub error: String,
+}
+
+/// Pass the lines of `reader` along with their numbers, starting at `first`.
+/// Lines that are not UTF-8 are recorded as damage and skipped; a read error
+/// is recorded and ends the reading. Returns false if `f` stopped early.
+pub fn read_lines(reader: impl BufRead, first: u64, damages: &mut Vec<Damage>,
+    mut f: impl FnMut(u64, String) -> Result<bool, Error>) -> Result<bool, Error>
+{
+    let mut line_nr = first;
+
+    for bytes in reader.split(b'\n') {
+        match bytes {
+            Ok(bytes) => {
+                match String::from_utf8(bytes) {
+                    Ok(line) => {
+                        if !f(line_nr, line)? {
+                            return Ok(false);
+                        }
+                    }
+                    Err(err) => {
+                        damages.push(Damage { line: Some(line_nr), error: err.to_string() });
+                    }
+                }
+            }
+            Err(err) => {
+                damages.push(Damage { line: Some(line_nr), error: format!("read error: {}", err) });
+                break;
+            }
+        }
+        line_nr += 1;
+    }
+
+    Ok(true)
+}
+
+impl SuperHist {
+    fn quarantine_dir(&self) -> PathBuf {
+        self.root.join("quarantine")
+    }
+
+    /// A fresh path under quarantine/ for something named `name`
+    pub(crate) fn quarantine_file(&self, name: &str) -> Result<PathBuf, Error> {
+        use chrono::Utc;
+        std::fs::create_dir_all(self.quarantine_dir())?;
+        let s = format!("{}-{}", Utc::now().format("%F-%H-%M-%S"), name);
+        Ok(self.quarantine_dir().join(s))
+    }
+
+    pub(crate) fn warn_damages(&self, segment: &Segment, damages: &[Damage]) {
+        if !damages.is_empty() {
+            eprintln!("superhist: warning - {}: skipped {} damaged parts, run `superhist fsck` for details",
+                segment.path(self).display(), damages.len());
+        }
+    }
+
+    /// Check db.json and all archives for records that cannot be read
+    pub(crate) fn fsck(&self, quarantine: bool, repair: bool) -> Result<(), Error> {
+        let lock = self.lock()?;
+        let mut total = 0;
+
+        for segment in self.segments()? {
+            let path = segment.path(self);
+            let mut good = vec![];
+            let mut bad_records = vec![];
+
+            let mut damages = self.scan_segment_lines(&segment, |item| {
+                if let SegmentItem::Entry((line_nr, line)) = item {
+                    match serde_json::de::from_str::<Event>(&line) {
+                        Ok(_) => good.push(line),
+                        Err(err) => bad_records.push(Damage { line: Some(line_nr), error: err.to_string() }),
+                    }
+                }
+                Ok(true)
+            })?;
+            damages.extend(bad_records);
+
+            if damages.is_empty() {
+                continue;
+            }
+
+            for damage in damages.iter() {
+                match damage.line {
+                    Some(line_nr) => println!("{}:{}: {}", path.display(), line_nr, damage.error),
+                    None => println!("{}: {}", path.display(), damage.error),
+                }
+            }
+            total += damages.len();
+
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if repair {
+                if let Segment::Live = segment {
+                    good.reverse();
+                }
+                let copy = self.quarantine_file(&name)?;
+                std::fs::copy(&path, &copy)?;
+                self.write_segment_lines(&segment, &good)?;
+                println!("{}: repaired, kept {} records, original saved as {}",
+                    path.display(), good.len(), copy.display());
+            } else if quarantine {
+                let moved = self.quarantine_file(&name)?;
+                std::fs::rename(&path, &moved)?;
+                let _ = std::fs::remove_file(self.segment_index_file(&segment));
+                let _ = std::fs::remove_file(self.segment_trigrams_file(&segment));
+                println!("{}: moved to {}", path.display(), moved.display());
+            }
+        }
+
+        lock.unlock()?;
+
+        if total > 0 && !repair && !quarantine {
+            return Err(Error::DamageFound(total));
+        }
+
+        Ok(())
+    }
+}
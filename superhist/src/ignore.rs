@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+
+use crate::config::Ignore;
+use crate::{Error, Event, Payload, SuperHist};
+
+/// Matches commands against the ignore settings
+pub struct IgnoreList {
+    commands: GlobSet,
+    regexes: RegexSet,
+    workdirs: GlobSet,
+    leading_space: bool,
+}
+
+impl IgnoreList {
+    pub fn new(config: &Ignore) -> Result<Self, Error> {
+        let mut commands = GlobSetBuilder::new();
+        for glob in config.commands.iter() {
+            commands.add(Glob::new(glob)?);
+        }
+
+        let mut workdirs = GlobSetBuilder::new();
+        for glob in config.workdirs.iter() {
+            workdirs.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+        }
+
+        Ok(IgnoreList {
+            commands: commands.build()?,
+            regexes: RegexSet::new(&config.regexes)?,
+            workdirs: workdirs.build()?,
+            leading_space: config.leading_space,
+        })
+    }
+
+    /// Whether a command is ignored. The text is taken as it was typed,
+    /// before trimming, and the workdir globs match either path of it.
+    pub fn is_ignored(&self, event: &Event) -> bool {
+        match &event.payload {
+            Payload::Command { text, workdir, physical_workdir, .. } => {
+                (self.leading_space && text.starts_with(' ')) || {
+                    let text = text.trim();
+                    self.commands.is_match(text) || self.regexes.is_match(text) ||
+                        self.workdirs.is_match(workdir) ||
+                        physical_workdir.as_ref().map_or(false, |workdir| self.workdirs.is_match(workdir))
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl SuperHist {
+    /// The index of the last ignored command of each terminal, until its
+    /// exit code comes in a later `add`
+    fn ignored_file(&self) -> PathBuf {
+        self.root.join("db.json.ignored")
+    }
+
+    /// Remove ignored commands from `events`, along with their exit codes,
+    /// whether in `events` or to come. Must be called under the lock.
+    pub(crate) fn drop_ignored(&self, ignore: &IgnoreList, events: &mut Vec<Event>) -> Result<(), Error> {
+        let path = self.ignored_file();
+        let mut pending : BTreeMap<String, u64> = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::de::from_str(&text).unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        let before = pending.clone();
+
+        events.retain(|event| {
+            match &event.payload {
+                Payload::Command { .. } if ignore.is_ignored(event) => {
+                    pending.insert(event.terminal.clone(), event.idx);
+                    false
+                }
+                Payload::Command { .. } => {
+                    // The exit code of an ignored command comes before the
+                    // next command of its terminal, if at all
+                    pending.remove(&event.terminal);
+                    true
+                }
+                Payload::ExitCode(_) if pending.get(&event.terminal) == Some(&event.idx) => {
+                    pending.remove(&event.terminal);
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        if pending != before {
+            if pending.is_empty() {
+                std::fs::remove_file(&path)?;
+            } else {
+                std::fs::write(&path, serde_json::ser::to_string(&pending)?)?;
+            }
+        }
+
+        Ok(())
+    }
+}
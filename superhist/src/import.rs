@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use filetime::FileTime;
+use regex::Regex;
+
+use crate::{Error, Event, Payload, SuperHist, UnixTime};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    /// The per-directory zsh fork, `: start workdir:0;command`
+    Perdir,
+    /// Stock zsh EXTENDED_HISTORY, `: start:elapsed;command`
+    Zsh,
+    /// bash history, optionally with `#timestamp` lines
+    Bash,
+    /// fish history YAML
+    Fish,
+    /// A JSON object per command, as written by `export`, or superhist
+    /// records
+    Jsonl,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "perdir" => Ok(ImportFormat::Perdir),
+            "zsh" => Ok(ImportFormat::Zsh),
+            "bash" => Ok(ImportFormat::Bash),
+            "fish" => Ok(ImportFormat::Fish),
+            "jsonl" => Ok(ImportFormat::Jsonl),
+            _ => Err(format!("unknown format {:?}", s)),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PERDIR_RE: Regex = Regex::new("^: ([0-9]+) ([^:]*):0;((.|\n)*)$").unwrap();
+    static ref ZSH_RE: Regex = Regex::new("^: ([0-9]+):([0-9]+);((.|\n)*)$").unwrap();
+    static ref BASH_TIME_RE: Regex = Regex::new("^#([0-9]+)$").unwrap();
+}
+
+/// Guess the format from the first line that is not empty
+fn detect(lines: &[String]) -> ImportFormat {
+    let first = match lines.iter().find(|line| !line.trim().is_empty()) {
+        Some(line) => line,
+        None => return ImportFormat::Bash,
+    };
+
+    if first.starts_with('{') {
+        ImportFormat::Jsonl
+    } else if first.starts_with("- cmd: ") {
+        ImportFormat::Fish
+    } else if ZSH_RE.is_match(first) {
+        ImportFormat::Zsh
+    } else if PERDIR_RE.is_match(first) {
+        ImportFormat::Perdir
+    } else {
+        ImportFormat::Bash
+    }
+}
+
+/// zsh writes bytes that collide with its internal tokens as this byte
+/// followed by the original XOR 32
+const ZSH_META: u8 = 0x83;
+
+fn unmetafy(line: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(line.len());
+    let mut iter = line.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == ZSH_META {
+            if let Some(&next) = iter.next() {
+                bytes.push(next ^ 32);
+            }
+        } else {
+            bytes.push(byte);
+        }
+    }
+    bytes
+}
+
+/// Split the contents of a history file into lines, decoding zsh's metafied
+/// bytes if needed. Returns the number of lines that were not valid UTF-8 and
+/// were decoded lossily.
+fn decode_lines(data: &[u8], metafied: bool) -> (Vec<String>, usize) {
+    let mut lines = vec![];
+    let mut invalid = 0;
+
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    if data.is_empty() {
+        return (lines, invalid);
+    }
+
+    for line in data.split(|byte| *byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = if metafied { unmetafy(line) } else { line.to_vec() };
+        lines.push(match String::from_utf8(line) {
+            Ok(line) => line,
+            Err(err) => {
+                invalid += 1;
+                String::from_utf8_lossy(err.as_bytes()).into_owned()
+            }
+        });
+    }
+
+    (lines, invalid)
+}
+
+/// A command read from another history, before it becomes an event
+struct Imported {
+    timestamp: Option<UnixTime>,
+    text: String,
+    workdir: String,
+    duration: Option<u64>,
+}
+
+/// Join the lines of multi-line commands, which zsh ends with a backslash
+fn join_continued(lines: &[String]) -> Vec<String> {
+    let mut bunch = String::new();
+    let mut open = false;
+    let mut bunches = vec![];
+
+    for line in lines {
+        if line.ends_with("\\") {
+            bunch += &line[0 .. line.len() - 1];
+            bunch += "\n";
+            open = true;
+            continue;
+        }
+        if open {
+            bunch += &line;
+            bunches.push(std::mem::replace(&mut bunch, String::new()));
+            open = false;
+        } else {
+            bunches.push(line.to_owned());
+        }
+    }
+
+    bunches
+}
+
+/// Parse both layouts of zsh history, line by line, since a file written by
+/// the stock zsh may have been continued by the fork. Stock zsh records how
+/// long the command ran instead of where.
+fn parse_zsh(lines: &[String]) -> Vec<Imported> {
+    let mut commands = vec![];
+
+    for bunch in join_continued(lines).iter() {
+        if let Some(captures) = ZSH_RE.captures(&bunch) {
+            commands.push(Imported {
+                timestamp: captures.get(1).unwrap().as_str().parse().ok(),
+                workdir: String::new(),
+                text: captures.get(3).unwrap().as_str().to_owned(),
+                duration: captures.get(2).unwrap().as_str().parse().ok(),
+            });
+        } else if let Some(captures) = PERDIR_RE.captures(&bunch) {
+            commands.push(Imported {
+                timestamp: captures.get(1).unwrap().as_str().parse().ok(),
+                workdir: captures.get(2).unwrap().as_str().to_owned(),
+                text: captures.get(3).unwrap().as_str().to_owned(),
+                duration: None,
+            });
+        }
+    }
+
+    commands
+}
+
+fn parse_bash(lines: &[String]) -> Vec<Imported> {
+    let mut commands = vec![];
+    let mut timestamp = None;
+
+    for line in lines {
+        if let Some(captures) = BASH_TIME_RE.captures(line) {
+            timestamp = captures.get(1).unwrap().as_str().parse().ok();
+            continue;
+        }
+        commands.push(Imported { timestamp, text: line.to_owned(), workdir: String::new(), duration: None });
+    }
+
+    commands
+}
+
+fn fish_unescape(s: &str) -> String {
+    let mut text = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some(c) => text.push(c),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+fn parse_fish(lines: &[String]) -> Vec<Imported> {
+    let mut commands : Vec<Imported> = vec![];
+
+    for line in lines {
+        if let Some(cmd) = line.strip_prefix("- cmd: ") {
+            commands.push(Imported {
+                timestamp: None,
+                text: fish_unescape(cmd),
+                workdir: String::new(),
+                duration: None,
+            });
+        } else if let Some(when) = line.strip_prefix("  when: ") {
+            if let Some(last) = commands.last_mut() {
+                last.timestamp = when.trim().parse().ok();
+            }
+        }
+    }
+
+    commands
+}
+
+fn parse_jsonl(lines: &[String]) -> Vec<Imported> {
+    let mut commands = vec![];
+
+    for line in lines {
+        if let Ok(Event { timestamp, payload: Payload::Command { text, workdir, duration, .. }, .. }) =
+            serde_json::de::from_str::<Event>(line)
+        {
+            commands.push(Imported { timestamp: Some(timestamp), text, workdir, duration });
+            continue;
+        }
+
+        let value : serde_json::Value = match serde_json::de::from_str(line) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let field = |names: &[&str]| names.iter().find_map(|name| value.get(*name));
+        let text = match field(&["command", "cmd", "text"]).and_then(|v| v.as_str()) {
+            Some(text) => text.to_owned(),
+            None => continue,
+        };
+        commands.push(Imported {
+            timestamp: field(&["timestamp", "when", "time"]).and_then(|v| v.as_u64()),
+            text,
+            workdir: field(&["workdir", "cwd", "directory"]).and_then(|v| v.as_str()).unwrap_or("").to_owned(),
+            duration: field(&["duration"]).and_then(|v| v.as_u64()),
+        });
+    }
+
+    commands
+}
+
+/// Terminal of the commands imported before imports named their source, all
+/// with index 0
+const LEGACY_IMPORT_TERMINAL: &str = "/dev/pts/999";
+
+/// FNV-1a, which unlike the std hashers is stable across releases
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash : u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.iter().chain(&[0xff]) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Index of an imported command, derived from its content so that it stays
+/// the same wherever the command appears in the file. Repeats of the same
+/// command at the same time are told apart by their occurrence.
+fn import_idx(command: &Imported, occurrence: u64) -> u64 {
+    let timestamp = command.timestamp.map(|t| t.to_string()).unwrap_or_default();
+    let hash = stable_hash(&[timestamp.as_bytes(), command.text.as_bytes(), &occurrence.to_le_bytes()]);
+
+    // Keep it exact in JSON readers that use doubles
+    hash & ((1 << 53) - 1)
+}
+
+impl SuperHist {
+    /// Import a history file of another shell or tool. Imported commands
+    /// get the terminal `import:<source>`, where the source defaults to the
+    /// file's path, and an index derived from their content, so that
+    /// importing the same or an overlapping file again skips them. Commands
+    /// imported from any file before sources were recorded are skipped by
+    /// their time and text. Commands without a time get consecutive times
+    /// ending at the file's modification time.
+    pub(crate) fn import(&self, pathname: &PathBuf, format: Option<ImportFormat>,
+        source: Option<String>) -> Result<(), Error>
+    {
+        let file = File::open(pathname)?;
+        let mtime = FileTime::from_last_modification_time(&file.metadata()?).unix_seconds() as UnixTime;
+        let source = match source {
+            Some(source) => source,
+            None => std::fs::canonicalize(pathname)?.to_string_lossy().into_owned(),
+        };
+        let terminal = format!("import:{}", source);
+
+        let mut data = vec![];
+        BufReader::new(file).read_to_end(&mut data)?;
+
+        let format = match format {
+            Some(format) => format,
+            None => detect(&decode_lines(&data, false).0),
+        };
+        let metafied = match format {
+            ImportFormat::Perdir | ImportFormat::Zsh => true,
+            _ => false,
+        };
+        let (lines, invalid) = decode_lines(&data, metafied);
+        if invalid > 0 {
+            eprintln!("superhist: warning - {}: {} lines are not valid UTF-8, replaced the invalid bytes",
+                pathname.display(), invalid);
+        }
+
+        let commands = match format {
+            ImportFormat::Perdir | ImportFormat::Zsh => parse_zsh(&lines),
+            ImportFormat::Bash => parse_bash(&lines),
+            ImportFormat::Fish => parse_fish(&lines),
+            ImportFormat::Jsonl => parse_jsonl(&lines),
+        };
+
+        let mut present = HashSet::new();
+        let mut legacy : HashMap<(UnixTime, String), u64> = HashMap::new();
+        for segment in self.segments()? {
+            self.read_segment(&segment, |event| {
+                if event.terminal == terminal {
+                    present.insert(event.idx);
+                } else if event.terminal == LEGACY_IMPORT_TERMINAL && event.idx == 0 {
+                    if let Payload::Command { text, .. } = event.payload {
+                        *legacy.entry((event.timestamp, text)).or_insert(0) += 1;
+                    }
+                }
+                Ok(true)
+            })?;
+        }
+
+        let mut untimed = commands.iter().filter(|command| command.timestamp.is_none()).count() as UnixTime;
+        let mut occurrences = HashMap::new();
+        let mut events = vec![];
+        let mut skipped = 0;
+        for command in commands {
+            let occurrence = occurrences.entry((command.timestamp, command.text.clone())).or_insert(0);
+            *occurrence += 1;
+            let idx = import_idx(&command, *occurrence);
+
+            let timestamp = match command.timestamp {
+                Some(timestamp) => timestamp,
+                None => {
+                    untimed -= 1;
+                    mtime.saturating_sub(untimed)
+                }
+            };
+
+            if present.contains(&idx) {
+                skipped += 1;
+                continue;
+            }
+            if let Some(count) = legacy.get_mut(&(timestamp, command.text.clone())) {
+                if *count > 0 {
+                    *count -= 1;
+                    skipped += 1;
+                    continue;
+                }
+            }
+
+            events.push(Event {
+                timestamp,
+                idx,
+                terminal: terminal.clone(),
+                host: None,
+                payload: Payload::Command {
+                    text: command.text,
+                    workdir: command.workdir,
+                    duration: command.duration,
+                    physical_workdir: None,
+                }
+            });
+        }
+
+        let imported = self.add(events)?;
+        println!("{}: imported {} commands, {} already present", pathname.display(), imported, skipped);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::crypt::{open_stored_line, seal_stored_line};
+use crate::filter::is_under;
+use crate::{Error, Event, Payload, Segment, SuperHist, UnixTime};
+
+/// Bumped whenever the layout or meaning of `SegmentIndex` changes, so that
+/// stale index files get rebuilt from their segment instead of trusted.
+const INDEX_VERSION: u32 = 3;
+
+/// An exit code whose command lives in an older segment. Kept so that `fc`
+/// can skip a segment entirely and still mark commands from older segments.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DanglingExit {
+    pub terminal: String,
+    pub idx: u64,
+    pub code: u32,
+    pub timestamp: UnixTime,
+}
+
+/// Summary of one history segment (db.json or an archive file), stored as
+/// YAML next to it. Everything here can be recomputed from the segment.
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct SegmentIndex {
+    /// Number of commands in the segment
+    pub count: u64,
+
+    /// Number of events of all kinds
+    #[serde(default)]
+    pub events: u64,
+
+    #[serde(default)]
+    pub version: u32,
+
+    /// Size of the segment file at the time it was indexed
+    #[serde(default)]
+    pub source_len: u64,
+
+    #[serde(default)]
+    pub min_timestamp: Option<UnixTime>,
+
+    #[serde(default)]
+    pub max_timestamp: Option<UnixTime>,
+
+    /// Number of commands per workdir, with symlinks resolved
+    #[serde(default)]
+    pub workdirs: BTreeMap<String, u64>,
+
+    #[serde(default)]
+    pub dangling_exits: Vec<DanglingExit>,
+}
+
+impl SegmentIndex {
+    fn new() -> Self {
+        SegmentIndex {
+            version: INDEX_VERSION,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_events_rev<'a>(events: impl Iterator<Item=&'a Event>) -> Self {
+        let mut index = SegmentIndex::new();
+        index.add_events_rev(events);
+        index
+    }
+
+    /// Account for events given newest first, the order in which segments
+    /// are read.
+    fn add_events_rev<'a>(&mut self, events: impl Iterator<Item=&'a Event>) {
+        let mut exits = HashMap::new();
+
+        for event in events {
+            self.events += 1;
+            self.add_timestamp(event.timestamp);
+
+            match &event.payload {
+                Payload::Command { .. } => {
+                    self.count += 1;
+                    self.add_workdir(&event.payload);
+                    exits.remove(&(event.terminal.clone(), event.idx));
+                }
+                Payload::ExitCode(code) => {
+                    exits.insert((event.terminal.clone(), event.idx), (*code, event.timestamp));
+                }
+                Payload::Start | Payload::Unknown => {}
+            }
+        }
+
+        for ((terminal, idx), (code, timestamp)) in exits.into_iter() {
+            self.dangling_exits.push(DanglingExit { terminal, idx, code, timestamp });
+        }
+    }
+
+    /// Account for events appended to db.json. Its exit codes are not
+    /// tracked, as `scan_commands` never skips db.json.
+    fn add_appended<'a>(&mut self, events: impl Iterator<Item=&'a Event>) {
+        for event in events {
+            self.events += 1;
+            self.add_timestamp(event.timestamp);
+
+            if let Payload::Command { .. } = &event.payload {
+                self.count += 1;
+                self.add_workdir(&event.payload);
+            }
+        }
+    }
+
+    fn add_workdir(&mut self, payload: &Payload) {
+        if let Some(workdir) = payload.physical_workdir() {
+            *self.workdirs.entry(workdir.to_owned()).or_insert(0) += 1;
+        }
+    }
+
+    fn add_timestamp(&mut self, timestamp: UnixTime) {
+        self.min_timestamp = Some(self.min_timestamp.map_or(timestamp, |t| t.min(timestamp)));
+        self.max_timestamp = Some(self.max_timestamp.map_or(timestamp, |t| t.max(timestamp)));
+    }
+
+    /// Number of commands that `fc` would consider for the given workdir
+    pub fn commands(&self, workdir: &Option<String>) -> u64 {
+        match workdir {
+            Some(workdir) => self.workdirs.get(workdir).cloned().unwrap_or(0),
+            None => self.count,
+        }
+    }
+
+    /// Number of commands in `dir` and below it
+    pub fn commands_under(&self, dir: &str) -> u64 {
+        self.workdirs.range(dir.to_owned() ..)
+            .take_while(|(workdir, _)| workdir.starts_with(dir))
+            .filter(|(workdir, _)| is_under(dir, workdir))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Whether every event in the segment is older than `start_time`
+    pub fn all_before(&self, start_time: UnixTime) -> bool {
+        self.max_timestamp.map_or(true, |t| t < start_time)
+    }
+
+    /// Whether no event in the segment is older than `start_time`
+    pub fn none_before(&self, start_time: UnixTime) -> bool {
+        self.min_timestamp.map_or(true, |t| t >= start_time)
+    }
+
+    fn is_current(&self, source: &Path) -> bool {
+        let len = std::fs::metadata(source).map(|m| m.len()).unwrap_or(0);
+        self.version == INDEX_VERSION && self.source_len == len
+    }
+}
+
+impl SuperHist {
+    pub(crate) fn main_db_index_file(&self) -> PathBuf {
+        self.root.join("db.idx.yaml")
+    }
+
+    pub(crate) fn segment_index_file(&self, segment: &Segment) -> PathBuf {
+        match segment {
+            Segment::Live => self.main_db_index_file(),
+            Segment::Archived(path) => PathBuf::from(format!("{}{}", path.display(), ".idx.yaml")),
+        }
+    }
+
+    fn load_segment_index(&self, segment: &Segment) -> Option<SegmentIndex> {
+        let text = std::fs::read_to_string(self.segment_index_file(segment)).ok()?;
+        let cipher = self.cipher().ok()?;
+        let text = open_stored_line(cipher, text.trim_end().to_owned()).ok()?;
+        let index: SegmentIndex = serde_yaml::from_str(&text).ok()?;
+        if index.is_current(&segment.path(self)) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn save_segment_index(&self, segment: &Segment, index: &SegmentIndex) -> Result<(), Error> {
+        let path = self.segment_index_file(segment);
+        let tmp_path = PathBuf::from(format!("{}{}", path.display(), ".tmp"));
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+        let mut file = BufWriter::new(file);
+        let cipher = self.cipher()?;
+        writeln!(&mut file, "{}", seal_stored_line(cipher, &serde_yaml::to_string(index)?))?;
+        file.flush()?;
+        drop(file);
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Compute the index of a segment by reading all of it
+    fn build_segment_index(&self, segment: &Segment) -> Result<SegmentIndex, Error> {
+        let source_len = std::fs::metadata(segment.path(self)).map(|m| m.len()).unwrap_or(0);
+        let mut events = vec![];
+        self.read_segment(segment, |event| {
+            events.push(event);
+            Ok(true)
+        })?;
+
+        let mut index = SegmentIndex::new();
+        index.source_len = source_len;
+        index.add_events_rev(events.iter());
+        Ok(index)
+    }
+
+    /// Get the index of a segment, rebuilding it if it is missing or stale
+    pub(crate) fn segment_index(&self, segment: &Segment) -> Result<SegmentIndex, Error> {
+        if let Some(index) = self.load_segment_index(segment) {
+            return Ok(index);
+        }
+
+        let index = self.build_segment_index(segment)?;
+        self.save_segment_index(segment, &index)?;
+        Ok(index)
+    }
+
+    /// Update the db.json index after `events` were appended to it. Must be
+    /// called under the lock, with `prev_len` being the size of db.json before
+    /// the append. Returns the updated index.
+    pub(crate) fn index_appended(&self, prev_len: u64, events: &[&Event]) -> Result<SegmentIndex, Error> {
+        let segment = Segment::Live;
+        let path = self.main_db_index_file();
+        let cipher = self.cipher()?;
+        let previous = std::fs::read_to_string(&path).ok()
+            .and_then(|text| open_stored_line(cipher, text.trim_end().to_owned()).ok())
+            .and_then(|text| serde_yaml::from_str::<SegmentIndex>(&text).ok())
+            .filter(|index| index.version == INDEX_VERSION && index.source_len == prev_len);
+
+        let index = match previous {
+            Some(mut index) => {
+                index.add_appended(events.iter().cloned());
+                index.source_len = std::fs::metadata(self.main_db_file())?.len();
+                index
+            }
+            None => self.build_segment_index(&segment)?,
+        };
+
+        self.save_segment_index(&segment, &index)?;
+        Ok(index)
+    }
+
+    /// Write the index of a freshly written archive segment, given its
+    /// events newest first.
+    pub(crate) fn index_archived(&self, path: &Path, events: &[Option<Event>]) -> Result<(), Error> {
+        let segment = Segment::Archived(path.to_owned());
+        let mut index = SegmentIndex::from_events_rev(events.iter().flatten());
+        index.source_len = std::fs::metadata(path)?.len();
+        self.save_segment_index(&segment, &index)
+    }
+
+    /// Rebuild the index and trigram files of a segment whose content changed
+    pub(crate) fn refresh_segment(&self, segment: &Segment) -> Result<(), Error> {
+        let index = self.build_segment_index(segment)?;
+        self.save_segment_index(segment, &index)?;
+        if let Segment::Archived(_) = segment {
+            self.retrigram(segment)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild all indices and trigram files from db.json and the archives
+    pub(crate) fn reindex(&self) -> Result<(), Error> {
+        let lock = self.lock()?;
+        for segment in self.segments()? {
+            self.refresh_segment(&segment)?;
+        }
+        lock.unlock()?;
+        Ok(())
+    }
+}
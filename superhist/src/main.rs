@@ -1,9 +1,10 @@
 use structopt::StructOpt;
+use std::cell::OnceCell;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::{collections::hash_map, path::PathBuf};
 use std::io::{Write};
 use std::fs::{OpenOptions, File};
-use std::io::{BufReader, BufRead};
+use std::io::{BufReader, Seek, SeekFrom};
 use std::io::BufWriter;
 use thiserror::Error;
 use regex::Regex;
@@ -17,6 +18,37 @@ use futures::StreamExt;
 use futures::FutureExt;
 use unicode_width::UnicodeWidthChar;
 
+mod blocks;
+mod canon;
+mod config;
+mod crypt;
+mod durable;
+mod export;
+mod filter;
+mod fsck;
+mod import;
+mod ignore;
+mod index;
+mod merge;
+mod picker;
+mod purge;
+mod rank;
+mod redact;
+mod schema;
+mod search;
+mod session;
+mod stats;
+
+use config::Config;
+use crypt::{open_stored_line, seal_stored_line, Cipher};
+use export::ExportFormat;
+use filter::{physical_dir, run_time, EventFilter, ExitFilter, FilterOpts};
+use ignore::IgnoreList;
+use import::ImportFormat;
+use index::SegmentIndex;
+use rank::{Frecency, RankMode, RankOpts};
+use fsck::{Damage, read_lines};
+
 #[derive(Error, Debug)]
 enum Error {
     #[error("I/O error: {0}")]
@@ -34,6 +66,39 @@ enum Error {
     #[error("YAML error: {0}")]
     YamlError(#[from] serde_yaml::Error),
 
+    #[error("regex error: {0}")]
+    RegexError(#[from] regex::Error),
+
+    #[error("glob error: {0}")]
+    GlobError(#[from] globset::Error),
+
+    #[error("archive {0:?} already exists")]
+    ArchiveExists(PathBuf),
+
+    #[error("cannot tell the host of {0:?}, see `superhist merge --help`")]
+    UnknownHost(PathBuf),
+
+    #[error("{0:?} was merged before as the history of host {1:?}")]
+    HostMismatch(PathBuf, String),
+
+    #[error("malformed block archive")]
+    BadArchive,
+
+    #[error("history is encrypted, but no key file is configured")]
+    NoKey,
+
+    #[error("malformed key file {0:?}")]
+    BadKey(PathBuf),
+
+    #[error("cannot decrypt, wrong key or damaged data")]
+    Decryption,
+
+    #[error("{0} damaged parts found, see `superhist fsck --help`")]
+    DamageFound(usize),
+
+    #[error("no session {0:?}, see `superhist session`")]
+    SessionNotFound(String),
+
     #[error("invalid parameters")]
     InvalidParams,
 }
@@ -41,14 +106,64 @@ enum Error {
 type UnixTime = u64;
 pub type Tty = File;
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Parse a number of seconds, optionally suffixed by s, m, h or d
+fn parse_duration(s: &str) -> Result<u64, Error> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+
+    let n : u64 = digits.parse().map_err(|_| Error::InvalidParams)?;
+    n.checked_mul(unit).ok_or(Error::InvalidParams)
+}
+
+/// Short human form of a number of seconds, at most 4 characters wide, so
+/// that 1000 days and more show as "999d"
+fn format_duration(secs: u64) -> String {
+    if secs < 100 {
+        format!("{}s", secs)
+    } else if secs < 100 * 60 {
+        format!("{}m", secs / 60)
+    } else if secs < 100 * 60 * 60 {
+        format!("{}h", secs / (60 * 60))
+    } else {
+        format!("{}d", std::cmp::min(secs / (24 * 60 * 60), 999))
+    }
+}
+
+/// See `schema` for how these are stored
+#[derive(Debug)]
 pub enum Payload {
     Start,
     Command {
         text: String,
         workdir: String,
+        /// Seconds the command ran, when known without an exit code, as
+        /// imported from stock zsh history
+        duration: Option<u64>,
+        /// The workdir with symlinks resolved, when it differs
+        physical_workdir: Option<String>,
     },
     ExitCode(u32),
+
+    /// Written by a newer version of superhist
+    Unknown,
+}
+
+impl Payload {
+    /// Where a command ran with symlinks resolved, as far as known. Workdirs
+    /// are matched by this.
+    pub fn physical_workdir(&self) -> Option<&str> {
+        match self {
+            Payload::Command { workdir, physical_workdir, .. } => {
+                Some(physical_workdir.as_deref().unwrap_or(workdir))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -98,24 +213,177 @@ impl Procedures {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug)]
 pub struct Event {
     pub timestamp: UnixTime,
     pub idx: u64,
     pub terminal: String,
+    /// The machine the event comes from, when merged from another root.
+    /// Events without one are from the local machine.
+    pub host: Option<String>,
     pub payload: Payload,
 }
 
 #[derive(StructOpt, Debug)]
 enum Command {
     Archive,
+    Reindex,
+    Migrate,
+    /// Create the key file configured for encryption
+    Keygen,
+    Fsck {
+        /// Move damaged segments out of the history, under quarantine/
+        #[structopt(long = "quarantine")]
+        quarantine: bool,
+
+        /// Rewrite damaged segments with only their readable records, keeping
+        /// a copy of the original under quarantine/
+        #[structopt(long = "repair")]
+        repair: bool,
+    },
+    /// Write the history in the format of another shell or as data
+    Export {
+        /// One of zsh, bash, fish, jsonl or csv
+        #[structopt(long = "format", default_value = "jsonl")]
+        format: ExportFormat,
+
+        #[structopt(flatten)]
+        filter: FilterOpts,
+
+        #[structopt(flatten)]
+        exit_filter: ExitFilter,
+
+        /// Write to this file rather than to stdout
+        #[structopt(short = "o")]
+        output: Option<PathBuf>,
+    },
+    /// Pick a command from the history interactively, and print it
+    Pick {
+        #[structopt(flatten)]
+        filter: FilterOpts,
+
+        #[structopt(flatten)]
+        exit_filter: ExitFilter,
+
+        #[structopt(flatten)]
+        rank: RankOpts,
+
+        /// Start with the commands of all directories rather than those of
+        /// the workdir
+        #[structopt(long = "global")]
+        global: bool,
+
+        /// Initial query
+        #[structopt(short = "q", long = "query", default_value = "")]
+        query: String,
+
+        /// Print the matching commands, best first, rather than picking one
+        #[structopt(long = "print-candidates")]
+        print_candidates: bool,
+    },
+    /// Take in the history of another root
+    Merge {
+        /// Host of the events of the other root that do not name one, if not
+        /// the one it was merged under before or the host its archives were
+        /// written on
+        #[structopt(long = "host")]
+        host: Option<String>,
+
+        other_root: PathBuf,
+    },
+    /// Remove matching events from db.json and all archives
+    Purge {
+        #[structopt(flatten)]
+        filter: FilterOpts,
+
+        /// Only list the events that would be removed
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Apply the redaction settings to the existing history
+    Redact {
+        /// Only list the commands that would be redacted
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Summarize the commands: the most common ones, failure rates, busiest
+    /// directories and times
+    Stats {
+        #[structopt(flatten)]
+        filter: FilterOpts,
+
+        #[structopt(flatten)]
+        exit_filter: ExitFilter,
+
+        /// How many of the most common commands, programs, directories and
+        /// days to list
+        #[structopt(short = "n", long = "limit", default_value = "10")]
+        limit: usize,
+
+        /// Print a JSON object rather than text
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// List the shell sessions, or replay the commands of one of them
+    Session {
+        /// Sessions of this host, as named when merged
+        #[structopt(long = "host")]
+        host: Option<String>,
+
+        /// Sessions of this terminal
+        #[structopt(long = "terminal")]
+        terminal: Option<String>,
+
+        /// List at most this many of the newest sessions
+        #[structopt(short = "n", long = "limit")]
+        limit: Option<usize>,
+
+        /// Session to replay, as listed
+        id: Option<String>,
+    },
+    /// Resolve symlinks in the workdirs of previously recorded commands
+    Recanonicalize {
+        /// Only list the commands that would change
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+    },
+    Search {
+        #[structopt(short = "e")]
+        regex: bool,
+
+        #[structopt(short = "i")]
+        ignore_case: bool,
+
+        #[structopt(short = "w")]
+        workdir: Option<String>,
+
+        /// Print at most this many commands, or all of them if 0
+        #[structopt(short = "n")]
+        limit: Option<usize>,
+
+        pattern: String,
+    },
     Import {
         #[structopt(short = "p")]
         hist_file: PathBuf,
+
+        /// One of perdir, zsh, bash, fish or jsonl. Detected by default.
+        #[structopt(long = "format")]
+        format: Option<ImportFormat>,
+
+        /// Name of the source, for telling apart what was already imported
+        /// from it. The path of the file by default, so a copy of a file
+        /// at another path needs the same source given to be deduplicated
+        /// against it.
+        #[structopt(long = "source")]
+        source: Option<String>,
     },
     FC {
-        #[structopt(short = "w")]
-        workdir: Option<String>,
+        #[structopt(flatten)]
+        filter: FilterOpts,
+
+        #[structopt(flatten)]
+        exit_filter: ExitFilter,
 
         #[structopt(short = "s")]
         start_nr: u64,
@@ -125,6 +393,9 @@ enum Command {
 
         #[structopt(short = "t")]
         start_time: u64,
+
+        #[structopt(flatten)]
+        rank: RankOpts,
     },
     Add {
         #[structopt(short = "x")]
@@ -368,9 +639,34 @@ impl SelectionState {
     }
 }
 
+/// A unit of history storage: the live db.json or a file under archive/
+pub enum Segment {
+    Live,
+    Archived(PathBuf),
+}
+
+/// What `SuperHist::scan_segment` passes along while reading a segment:
+/// either parsed events or raw lines, and summaries of blocks.
+pub enum SegmentItem<'a, T = Event> {
+    Block(&'a SegmentIndex),
+    Entry(T),
+}
+
+impl Segment {
+    fn path(&self, superhist: &SuperHist) -> PathBuf {
+        match self {
+            Segment::Live => superhist.main_db_file(),
+            Segment::Archived(path) => path.clone(),
+        }
+    }
+}
+
 pub struct SuperHist {
     root: PathBuf,
+    config: Config,
     selection_state: SelectionState,
+    /// Loaded on first use, so that all segments are read with the same key
+    cipher: OnceCell<Option<Cipher>>,
 }
 
 use crossterm::{
@@ -408,11 +704,15 @@ impl ProcedureMode {
 }
 
 impl SuperHist {
-    fn new(path: PathBuf) -> Self {
-        SuperHist {
+    fn new(path: PathBuf) -> Result<Self, Error> {
+        let config = Config::load(&path.join("config.yaml"))?;
+
+        Ok(SuperHist {
             root: path,
+            config,
             selection_state: Default::default(),
-        }
+            cipher: OnceCell::new(),
+        })
     }
 
     fn lock_path(&self) -> PathBuf {
@@ -437,9 +737,9 @@ impl SuperHist {
 
     fn archive_file(&self)  -> Result<PathBuf, Error>  {
         use chrono::Utc;
-        let s = format!("{}-{}.xz",
+        let s = format!("{}-{}.xzb",
             Utc::now().format("%F-%H-%M-%S"),
-            hostname::get()?.into_string().unwrap());
+            merge::local_host()?);
         Ok(self.archive_dir().join(s))
     }
 
@@ -457,89 +757,277 @@ impl SuperHist {
         Ok(FileLock::lock(path.to_str().unwrap(), true, options)?)
     }
 
-    /// Take current file, reverse its record and keep it xz-compressed under archive/
+    /// Take current file, reverse its record and keep it block-compressed under archive/
     fn archive(&self) -> Result<(), Error> {
+        let lock = self.lock()?;
+        self.archive_locked()?;
+        lock.unlock()?;
+        Ok(())
+    }
+
+    fn archive_locked(&self) -> Result<(), Error> {
         std::fs::create_dir_all(self.archive_dir())?;
 
-        let lock = self.lock()?;
         let archive = self.archive_file()?;
-
-        let writer = BufWriter::new(File::create(archive)?);
-        let mut compressor = XzEncoder::new(writer, 9);
+        if archive.exists() {
+            return Err(Error::ArchiveExists(archive));
+        }
 
         {
-            let reader = BufReader::new(File::open(self.main_db_file())?);
+            let cipher = self.cipher()?;
+            let (lines, mut damages) = self.segment_lines(&Segment::Live)?;
+
+            // Damaged records are left out of the archive, but kept aside
             let mut vx = vec![];
-            for line in reader.lines() {
-                vx.push(line?);
+            let mut events = vec![];
+            let mut bad_lines = vec![];
+            for line in lines.into_iter().rev() {
+                match serde_json::de::from_str::<Event>(&line) {
+                    Ok(event) => {
+                        vx.push(line);
+                        events.push(Some(event));
+                    }
+                    Err(err) => {
+                        damages.push(Damage { line: None, error: err.to_string() });
+                        bad_lines.push(line);
+                    }
+                }
             }
 
-            for line in vx.iter().rev() {
-                compressor.write(line.as_bytes())?;
-                compressor.write("\n".as_bytes())?;
+            if !bad_lines.is_empty() {
+                let mut file = BufWriter::new(File::create(self.quarantine_file("db.json.bad")?)?);
+                for line in bad_lines {
+                    writeln!(&mut file, "{}", seal_stored_line(cipher, &line))?;
+                }
+                file.flush()?;
             }
-            compressor.flush()?;
+            self.warn_damages(&Segment::Live, &damages);
+
+            blocks::write(&archive, &vx, &events, cipher)?;
+            self.index_archived(&archive, &events)?;
+            self.trigrams_archived(&archive, &events)?;
         }
 
         OpenOptions::new().write(true).truncate(true).open(self.main_db_file())?;
 
         std::fs::remove_file(self.main_db_file())?;
+        let _ = std::fs::remove_file(self.main_db_index_file());
 
-        lock.unlock()?;
         Ok(())
     }
 
-    /// Import an old zsh history file
-    fn import(&self, pathname: &PathBuf) -> Result<(), Error> {
-        let reader = BufReader::new(File::open(pathname)?);
+    /// All history segments, newest first
+    fn segments(&self) -> Result<Vec<Segment>, Error> {
+        let mut segments = vec![];
+        if self.main_db_file().exists() {
+            segments.push(Segment::Live);
+        }
 
-        // Read current file
-        lazy_static::lazy_static! {
-            static ref RE: Regex = Regex::new("^: ([0-9]+) ([^:]*):0;((.|\n)*)$").unwrap();
+        let archive = self.archive_dir();
+        if archive.exists() {
+            let mut v = vec![];
+            for entry in std::fs::read_dir(&archive)? {
+                let path = entry?.path();
+                let s = path.to_string_lossy();
+                if s.ends_with(".idx.yaml") || s.ends_with(".tri") || s.ends_with(".tmp") {
+                    continue;
+                }
+                v.push(path.to_owned());
+            }
+            v.sort();
+            segments.extend(v.into_iter().rev().map(Segment::Archived));
         }
 
-        let mut bunch = String::new();
-        let mut open = false;
-        let mut bunches = vec![];
+        Ok(segments)
+    }
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                if line.ends_with("\\") {
-                    bunch += &line[0 .. line.len() - 1];
-                    bunch += "\n";
-                    open = true;
-                    continue;
+    /// Pass the events of a segment to `f`, newest first, until it returns false
+    fn read_segment(&self, segment: &Segment, mut f: impl FnMut(Event) -> Result<bool, Error>) -> Result<(), Error> {
+        self.scan_segment(segment, |item| {
+            match item {
+                SegmentItem::Block(_) => Ok(true),
+                SegmentItem::Entry(event) => f(event),
+            }
+        })
+    }
+
+    /// Like `read_segment`, but for block archives `f` also gets the summary of
+    /// each block ahead of its events, and returning false skips that block.
+    /// Damaged parts of the segment are skipped and reported.
+    fn scan_segment(&self, segment: &Segment, mut f: impl FnMut(SegmentItem) -> Result<bool, Error>) -> Result<(), Error> {
+        let mut bad_records = vec![];
+        let mut damages = self.scan_segment_lines(segment, |item| {
+            match item {
+                SegmentItem::Block(index) => f(SegmentItem::Block(index)),
+                SegmentItem::Entry((line_nr, line)) => {
+                    match serde_json::de::from_str::<Event>(line.as_str()) {
+                        Ok(event) => f(SegmentItem::Entry(event)),
+                        Err(err) => {
+                            bad_records.push(Damage { line: Some(line_nr), error: err.to_string() });
+                            Ok(true)
+                        }
+                    }
                 }
-                if open {
-                    bunch += &line;
-                    bunches.push(std::mem::replace(&mut bunch, String::new()));
-                    open = false;
-                } else {
-                    bunches.push(line.to_owned());
+            }
+        })?;
+
+        damages.extend(bad_records);
+        self.warn_damages(segment, &damages);
+
+        Ok(())
+    }
+
+    /// Like `scan_segment`, but on the stored lines and their numbers, without
+    /// parsing them. Encrypted lines are passed decrypted. Parts that cannot
+    /// be read are returned rather than passed.
+    fn scan_segment_lines(&self, segment: &Segment, mut f: impl FnMut(SegmentItem<(u64, String)>) -> Result<bool, Error>) -> Result<Vec<Damage>, Error> {
+        let cipher = self.cipher()?;
+        let mut damages = vec![];
+        let mut sealed_damages = vec![];
+
+        let mut f = |item: SegmentItem<(u64, String)>| {
+            match item {
+                SegmentItem::Entry((line_nr, line)) => {
+                    match open_stored_line(cipher, line) {
+                        Ok(line) => f(SegmentItem::Entry((line_nr, line))),
+                        Err(Error::Decryption) => {
+                            sealed_damages.push(Damage { line: Some(line_nr), error: Error::Decryption.to_string() });
+                            Ok(true)
+                        }
+                        Err(err) => Err(err),
+                    }
                 }
+                SegmentItem::Block(index) => f(SegmentItem::Block(index)),
             }
-        }
+        };
+
+        match segment {
+            Segment::Live => {
+                let reader = BufReader::new(File::open(self.main_db_file())?);
+
+                // Read current file
+                let mut vx = vec![];
+                read_lines(reader, 1, &mut damages, |line_nr, line| {
+                    vx.push((line_nr, line));
+                    Ok(true)
+                })?;
 
-        let mut events = vec![];
-        for bunch in bunches.iter() {
-            if let Some(captures) = RE.captures(&bunch) {
-                let ts = captures.get(1).unwrap().as_str();
-                let workdir = captures.get(2).unwrap().as_str();
-                let command = captures.get(3).unwrap().as_str();
-                let event = Event {
-                    timestamp: ts.parse().unwrap(),
-                    idx: 0,
-                    terminal: "/dev/pts/999".to_owned(),
-                    payload: Payload::Command {
-                        text: command.to_owned(),
-                        workdir: workdir.to_owned(),
+                for entry in vx.into_iter().rev() {
+                    if !f(SegmentItem::Entry(entry))? {
+                        break;
+                    }
+                }
+            }
+            Segment::Archived(path) if path.to_string_lossy().ends_with(".xzb") => {
+                let mut file = File::open(path)?;
+                let index = match blocks::read_index(&mut file, cipher) {
+                    Ok(index) => index,
+                    Err(Error::NoKey) => return Err(Error::NoKey),
+                    Err(err) => {
+                        // Recover what we can by reading the blocks in sequence
+                        damages.push(Damage { line: None, error: format!("block index: {}", err) });
+                        file.seek(SeekFrom::Start(0))?;
+                        let decompressor = BufReader::new(XzDecoder::new_multi_decoder(BufReader::new(file)));
+                        read_lines(decompressor, 1, &mut damages, |line_nr, line| f(SegmentItem::Entry((line_nr, line))))?;
+                        damages.extend(sealed_damages);
+                        return Ok(damages);
                     }
                 };
-                events.push(event);
+
+                for block in index {
+                    if !f(SegmentItem::Block(&block.summary))? {
+                        continue;
+                    }
+
+                    let data = match blocks::read_block(&mut file, &block, cipher) {
+                        Ok(data) => data,
+                        Err(Error::NoKey) => return Err(Error::NoKey),
+                        Err(err) => {
+                            damages.push(Damage {
+                                line: None,
+                                error: format!("block at lines {}-{}: {}", block.first_line + 1,
+                                    block.first_line + block.lines, err),
+                            });
+                            continue;
+                        }
+                    };
+
+                    let cont = read_lines(data.as_slice(), block.first_line + 1, &mut damages,
+                        |line_nr, line| f(SegmentItem::Entry((line_nr, line))))?;
+                    if !cont {
+                        break;
+                    }
+                }
+            }
+            Segment::Archived(path) => {
+                let reader = BufReader::new(File::open(path)?);
+                if path.to_string_lossy().ends_with(".xz") {
+                    let decompressor = BufReader::new(XzDecoder::new(reader));
+                    read_lines(decompressor, 1, &mut damages, |line_nr, line| f(SegmentItem::Entry((line_nr, line))))?;
+                } else {
+                    read_lines(reader, 1, &mut damages, |line_nr, line| f(SegmentItem::Entry((line_nr, line))))?;
+                }
             }
         }
 
-        self.add(events)?;
+        damages.extend(sealed_damages);
+        Ok(damages)
+    }
+
+    /// All readable lines of a segment in the order they are stored in: oldest
+    /// first for db.json, newest first for archives. Also returns the damage.
+    fn segment_lines(&self, segment: &Segment) -> Result<(Vec<String>, Vec<Damage>), Error> {
+        let mut lines = vec![];
+        let damages = self.scan_segment_lines(segment, |item| {
+            if let SegmentItem::Entry((_, line)) = item {
+                lines.push(line);
+            }
+            Ok(true)
+        })?;
+
+        if let Segment::Live = segment {
+            lines.reverse();
+        }
+
+        Ok((lines, damages))
+    }
+
+    /// Replace the content of a segment with `lines`, given in stored order,
+    /// keeping the segment's format, and encrypted if configured. Must be
+    /// called under the lock.
+    fn write_segment_lines(&self, segment: &Segment, lines: &[String]) -> Result<(), Error> {
+        let cipher = self.cipher()?;
+        let path = segment.path(self);
+        let tmp_path = PathBuf::from(format!("{}{}", path.display(), ".tmp"));
+        let s = path.to_string_lossy();
+
+        if s.ends_with(".xzb") {
+            let events : Vec<Option<Event>> = lines.iter()
+                .map(|line| serde_json::de::from_str(line).ok())
+                .collect();
+            blocks::write(&tmp_path, lines, &events, cipher)?;
+        } else {
+            let write_lines = |writer: &mut dyn Write| -> Result<(), Error> {
+                for line in lines {
+                    writer.write_all(seal_stored_line(cipher, line).as_bytes())?;
+                    writer.write_all("\n".as_bytes())?;
+                }
+                Ok(())
+            };
+
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            if s.ends_with(".xz") {
+                let mut compressor = XzEncoder::new(writer, 9);
+                write_lines(&mut compressor)?;
+                compressor.finish()?.flush()?;
+            } else {
+                write_lines(&mut writer)?;
+                writer.flush()?;
+            }
+        }
+
+        std::fs::rename(tmp_path, path)?;
+        self.refresh_segment(segment)?;
 
         Ok(())
     }
@@ -859,8 +1347,22 @@ impl SuperHist {
         Ok((r, opt_mtime))
     }
 
-    /// Add event to the current file
-    fn add(&self, mut events: Vec<Event>) -> Result<(), Error> {
+    /// Add events to the current file, all or none of them. Returns how many
+    /// were written, as ignored and empty commands are left out.
+    fn add(&self, mut events: Vec<Event>) -> Result<usize, Error> {
+        let lock = self.lock()?;
+        self.recover_main_db()?;
+
+        for event in events.iter_mut() {
+            if let Payload::Command { workdir, physical_workdir: physical_workdir @ None, .. } = &mut event.payload {
+                *physical_workdir = physical_dir(workdir);
+            }
+        }
+
+        let ignore = IgnoreList::new(&self.config.ignore)?;
+        self.drop_ignored(&ignore, &mut events)?;
+
+        let redactor = self.redactor()?;
         for event in events.iter_mut() {
             match &mut event.payload {
                 Payload::Command { text, .. }  => {
@@ -868,10 +1370,15 @@ impl SuperHist {
                 }
                 _ => { }
             }
+            if let Some(redactor) = &redactor {
+                redactor.redact_event(event);
+            }
         }
 
-        let lock = self.lock()?;
-        let mut file = OpenOptions::new().create(true).append(true).open(self.main_db_file())?;
+        let cipher = self.cipher()?;
+        let prev_len = std::fs::metadata(self.main_db_file()).map(|m| m.len()).unwrap_or(0);
+        let mut batch = String::new();
+        let mut written = vec![];
         for event in events.iter() {
             match &event.payload {
                 Payload::Command { text, .. }  => {
@@ -882,244 +1389,364 @@ impl SuperHist {
                 _ => { }
             }
 
-            let string = format!("{}\n", serde_json::ser::to_string(&event)?);
-            file.write(string.as_bytes())?;
+            let line = serde_json::ser::to_string(&event)?;
+            batch += &format!("{}\n", seal_stored_line(cipher, &line));
+            written.push(event);
+        }
+
+        if written.is_empty() {
+            lock.unlock()?;
+            return Ok(0);
+        }
+
+        // The whole batch goes in a single write, undone on the next call if
+        // it did not complete.
+        self.append_main_db(prev_len, batch.as_bytes())?;
+        let index = self.index_appended(prev_len, &written)?;
+
+        let now = chrono::Utc::now().timestamp() as UnixTime;
+        if self.config.rotation.is_due(&index, now) && !self.archive_file()?.exists() {
+            self.archive_locked()?;
         }
         lock.unlock()?;
-        Ok(())
+        Ok(written.len())
     }
 
-    /// Somewhat behave like the 'fc' command for the full database
-    fn fc(&self, workdir: &Option<String>, mut nr: u64, fetch: Option<u64>, start_time: u64) -> Result<(), Error> {
-        let full_timestamp = std::env::var("SUPERHIST_FC__FULL_TIMESTAMP").is_ok();
-
-        let mut exits = std::collections::HashMap::new();
+    /// Pass the commands recorded before `start_time` to `f`, newest first,
+    /// along with their exit code and the time it was recorded, until `f`
+    /// returns false. `visit` gets the summary of each segment and block
+    /// ahead of its events, and decides whether to read it at all.
+    fn scan_commands(&self, start_time: Option<UnixTime>, mut visit: impl FnMut(&SegmentIndex) -> bool,
+        mut f: impl FnMut(Event, Option<(u32, UnixTime)>) -> Result<bool, Error>) -> Result<(), Error>
+    {
         type ExitMap = HashMap<(String, u64), (u32, UnixTime)>;
-        let filter_func = |exits: &mut ExitMap, event: &Event, start_time: &Option<u64>| -> bool {
-            if let Some(start_time) = start_time {
-                if event.timestamp >= *start_time {
-                    return false;
-                }
+        let mut exits : ExitMap = std::collections::HashMap::new();
+        let before = |timestamp: UnixTime| start_time.map_or(true, |start_time| timestamp < start_time);
+        let mut stop = false;
+
+        let mut visit = |index: &SegmentIndex, exits: &mut ExitMap| -> bool {
+            if visit(index) {
+                return true;
             }
 
-            match &event.payload {
-                Payload::Command { workdir: command_workdir, .. } => {
-                    if let Some(workdir) = workdir {
-                        command_workdir == workdir
-                    } else {
-                        true
-                    }
-                }
-                Payload::ExitCode(code) => {
-                    exits.insert((event.terminal.clone(), event.idx), (*code, event.timestamp));
-                    false
-                }
-                _ => {
-                    false
+            // A part that is not read may still hold exit codes of commands
+            // from older parts.
+            for exit in index.dangling_exits.iter() {
+                if before(exit.timestamp) {
+                    exits.insert((exit.terminal.clone(), exit.idx), (exit.code, exit.timestamp));
                 }
             }
+            false
         };
 
-        let mut buffer = std::io::BufWriter::with_capacity(0x10000, std::io::stdout());
-        let mut hashset = std::collections::HashSet::new();
-        let stop = AtomicBool::new(false);
-
-        let mut print_func = |exits: &ExitMap, event: Event, nr: &mut u64| -> Result<(), Error> {
-            if let Payload::Command { text, .. } = event.payload {
-                let key = (event.terminal.clone(), event.idx);
-                if !hashset.contains(&text) {
-                    let (print_nr, matching) = if let Some(fetch_nr) = fetch {
-                        if fetch_nr == *nr {
-                            stop.store(true, Ordering::SeqCst);
-                        }
-                        (false, fetch_nr == *nr)
-                    } else {
-                        (true, true)
-                    };
-                    if matching {
-                        if print_nr {
-                            buffer.write(&format!("{} ", color::Fg(color::Rgb(60, 60, 60))).as_bytes())?;
-                            buffer.write(&format!("{:width$}  ", nr, width=6).as_bytes())?;
-                            use chrono::prelude::*;
-                            let naive = NaiveDateTime::from_timestamp(event.timestamp as i64, 0);
-                            let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-                            let converted: DateTime<Local> = DateTime::from(datetime);
-
-                            use termion::color;
-                            buffer.write(&format!("{}{} ",
-                                    color::Fg(color::Rgb(100, 100, 100)),
-                                    converted.format("%d.%m.%y")).as_bytes())?;
-
-                            if full_timestamp {
-                                buffer.write(&format!("{}{} ",
-                                        color::Fg(color::Rgb(100, 100, 100)),
-                                        converted.format("%H:%M:%S")).as_bytes())?;
+        // Only db.json can change under our feet, so hold the lock until
+        // we are done with it.
+        let mut lock = Some(self.lock()?);
+
+        for segment in self.segments()? {
+            let index = self.segment_index(&segment)?;
+
+            // db.json is always read, as its index does not track the exit
+            // codes appended to it for commands already archived.
+            if matches!(segment, Segment::Live) || visit(&index, &mut exits) {
+                self.scan_segment(&segment, |item| {
+                    match item {
+                        SegmentItem::Block(index) => Ok(visit(index, &mut exits)),
+                        SegmentItem::Entry(event) => {
+                            if !before(event.timestamp) {
+                                return Ok(true);
                             }
 
-                            if let Some((exitcode, _timestamp)) = exits.get(&key) {
-                                if *exitcode == 0 {
-                                    buffer.write(&format!("{}  ", color::Fg(color::Reset)).as_bytes())?;
-                                } else {
-                                    buffer.write(&format!("{}x{} ",
-                                            color::Fg(color::Rgb(255, 0, 0)),
-                                            color::Fg(color::Reset)).as_bytes())?;
+                            match &event.payload {
+                                Payload::Command { .. } => {
+                                    let exit = exits.get(&(event.terminal.clone(), event.idx)).cloned();
+                                    stop = !f(event, exit)?;
                                 }
-                                buffer.write(&format!("{} ", color::Fg(color::Rgb(240, 240, 240))).as_bytes())?;
-                            } else {
-                                buffer.write(&format!("{}   ", color::Fg(color::Rgb(170, 170, 170))).as_bytes())?;
+                                Payload::ExitCode(code) => {
+                                    exits.insert((event.terminal.clone(), event.idx), (*code, event.timestamp));
+                                }
+                                _ => {}
                             }
-                            buffer.write(text.replace("\n", "\\n").as_bytes())?;
-                        } else {
-                            buffer.write(text.as_bytes())?;
+                            Ok(!stop)
                         }
-                        buffer.write("\n".as_bytes())?;
                     }
-                    hashset.insert(text);
-                }
-                *nr += 1;
+                })?;
             }
-            Ok(())
-        };
 
-        let lock = self.lock()?;
-        if self.main_db_file().exists() {
-            let reader = BufReader::new(File::open(self.main_db_file())?);
-
-            // Read current file
-            let mut vx = vec![];
-            for line in reader.lines() {
-                vx.push(line?);
+            if let Segment::Live = segment {
+                if let Some(lock) = lock.take() {
+                    lock.unlock()?;
+                }
             }
 
-            for line in vx.iter().rev() {
-                let event : Event = serde_json::de::from_str(line)?;
-                if filter_func(&mut exits, &event, &Some(start_time)) {
-                    print_func(&exits, event, &mut nr)?;
-                }
-                if stop.load(Ordering::SeqCst) {
-                    break;
-                }
+            if stop {
+                break;
             }
+        }
 
-            lock.unlock()?;
+        Ok(())
+    }
+
+    /// Somewhat behave like the 'fc' command for the full database. Commands
+    /// of the filter's workdir are numbered, and the other criteria only pick
+    /// which of them are printed, so that numbers stay the same for fetching.
+    fn fc(&self, filter: &EventFilter, exit_filter: &ExitFilter, rank: &RankOpts, nr: u64,
+        fetch: Option<u64>, start_time: u64) -> Result<(), Error>
+    {
+        if rank.mode == RankMode::Frecency {
+            return self.fc_ranked(filter, exit_filter, rank, nr, fetch, start_time);
         }
 
-        // Read archive in reverse
-        let archive = self.archive_dir();
-        if archive.exists() {
-            let mut v = vec![];
-            for entry in std::fs::read_dir(&archive)? {
-                v.push(entry?.path().to_owned());
-            }
-            v.sort();
+        let full_timestamp = std::env::var("SUPERHIST_FC__FULL_TIMESTAMP").is_ok();
 
-            'next: for path in v.iter().rev() {
-                let s = path.to_string_lossy();
-                if s.ends_with(".idx.yaml") {
-                    continue;
+        let mut buffer = std::io::BufWriter::with_capacity(0x10000, std::io::stdout());
+        let mut hashset = std::collections::HashSet::new();
+        let stop = AtomicBool::new(false);
+        let nr = std::cell::Cell::new(nr);
+
+        let mut print_func = |exit: Option<(u32, UnixTime)>, event: Event| -> Result<(), Error> {
+            let filter_matches = filter.matches(&event) && exit_filter.matches(&event, exit);
+            if let Payload::Command { text, .. } = &event.payload {
+                // Commands filtered out still take a number, so that
+                // numbering stays the same as the one used for skipping.
+                if filter_matches && !hashset.contains(text) {
+                    if let Some(fetch_nr) = fetch {
+                        if fetch_nr == nr.get() {
+                            stop.store(true, Ordering::SeqCst);
+                            write_fc_entry(&mut buffer, None, &event, exit, full_timestamp)?;
+                        }
+                    } else {
+                        write_fc_entry(&mut buffer, Some(nr.get()), &event, exit, full_timestamp)?;
+                    }
+                    hashset.insert(text.clone());
                 }
+                nr.set(nr.get() + 1);
+            }
+            Ok(())
+        };
 
-                let idx_path = PathBuf::from(format!("{}{}", path.display(), ".idx.yaml"));
-                if s.ends_with(".xz") {
-                    if workdir.is_none() && fetch.is_some() {
-                        #[derive(Serialize, Deserialize)]
-                        struct YamlCache {
-                            count: u64,
-                        }
+        // Decide from the summary of a segment or a block whether it needs
+        // to be read at all.
+        let visit = |index: &SegmentIndex| -> bool {
+            if !filter.may_match(index) {
+                // Nothing printed from here, and skipped alike when fetching
+                false
+            } else if index.none_before(start_time) {
+                // Everything here is filtered out by time
+                false
+            } else if index.all_before(start_time) && fetch.is_some() &&
+                nr.get() + filter.indexed_commands(index) <= fetch.unwrap()
+            {
+                // Skip this whole part because it will not match
+                // the index we are seeking.
+                nr.set(nr.get() + filter.indexed_commands(index));
+                false
+            } else if index.all_before(start_time) && fetch.is_none() &&
+                filter.indexed_commands(index) == 0
+            {
+                // Nothing to print from here
+                false
+            } else {
+                true
+            }
+        };
 
-                        let cache = if !idx_path.exists() {
-                            let mut count = 0u64;
-                            let reader = BufReader::new(File::open(&path)?);
-                            let decompressor = BufReader::new(XzDecoder::new(reader));
+        self.scan_commands(Some(start_time), visit, |event, exit| {
+            let matches = match event.payload.physical_workdir() {
+                Some(workdir) => filter.workdir_matches(workdir),
+                None => true,
+            };
+            if matches {
+                print_func(exit, event)?;
+            }
+            Ok(!stop.load(Ordering::SeqCst))
+        })?;
 
-                            for line in decompressor.lines() {
-                                let event : Event = serde_json::de::from_str(line?.as_str())?;
-                                if filter_func(&mut exits, &event, &None) {
-                                    count += 1;
-                                }
-                            }
+        buffer.flush()?;
 
-                            let file = OpenOptions::new().write(true).create(true).truncate(true).open(idx_path)?;
-                            let mut file = BufWriter::new(file);
-                            let val = YamlCache {
-                                count,
-                            };
-                            writeln!(&mut file, "{}", serde_yaml::to_string(&val)?)?;
-                            val
-                        } else {
-                            serde_yaml::from_reader(&File::open(idx_path)?)?
-                        };
-
-                        if let Some(fetch_nr) = fetch {
-                            if nr + cache.count < fetch_nr {
-                                // Skip this whole archived history file because it will not match
-                                // the index we are seeking.
-                                nr += cache.count;
-                                continue 'next;
-                            }
-                        }
-                    }
+        Ok(())
+    }
 
-                    let reader = BufReader::new(File::open(path)?);
-                    let decompressor = BufReader::new(XzDecoder::new(reader));
+    /// Like `fc`, but numbering the matching commands by frecency as of
+    /// `start_time`, which needs all of them to be read first. Each command
+    /// is shown as of its latest use.
+    fn fc_ranked(&self, filter: &EventFilter, exit_filter: &ExitFilter, rank: &RankOpts, nr: u64,
+        fetch: Option<u64>, start_time: u64) -> Result<(), Error>
+    {
+        let full_timestamp = std::env::var("SUPERHIST_FC__FULL_TIMESTAMP").is_ok();
+        let mut frecency = Frecency::new(start_time, rank.rank_workdir.clone());
+        let mut latest = vec![];
 
-                    for line in decompressor.lines() {
-                        let event : Event = serde_json::de::from_str(line?.as_str())?;
-                        if filter_func(&mut exits, &event, &Some(start_time)) {
-                            print_func(&exits, event, &mut nr)?;
-                        }
-                        if stop.load(Ordering::SeqCst) {
-                            break;
-                        }
-                    }
-                } else {
-                    let reader = BufReader::new(File::open(path)?);
-                    for line in reader.lines() {
-                        let event : Event = serde_json::de::from_str(line?.as_str())?;
-                        if filter_func(&mut exits, &event, &Some(start_time)) {
-                            print_func(&exits, event, &mut nr)?;
-                        }
-                        if stop.load(Ordering::SeqCst) {
-                            break;
-                        }
-                    }
+        self.scan_commands(Some(start_time), |index| filter.may_match(index) && !index.none_before(start_time),
+            |event, exit| {
+                if filter.matches(&event) && exit_filter.matches(&event, exit) && frecency.add(&event, exit) {
+                    latest.push((event, exit));
                 }
-                if stop.load(Ordering::SeqCst) {
+                Ok(true)
+            })?;
+
+        let mut ranked : Vec<_> = latest.into_iter().map(|(event, exit)| {
+            let score = match &event.payload {
+                Payload::Command { text, .. } => frecency.score(text),
+                _ => 0.0,
+            };
+            (score, event, exit)
+        }).collect();
+        // Stable, so that equal scores stay newest first
+        ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut buffer = std::io::BufWriter::with_capacity(0x10000, std::io::stdout());
+        for (offset, (_, event, exit)) in ranked.iter().enumerate() {
+            let entry_nr = nr + offset as u64;
+            match fetch {
+                Some(fetch_nr) if fetch_nr == entry_nr => {
+                    write_fc_entry(&mut buffer, None, event, *exit, full_timestamp)?;
                     break;
                 }
+                Some(_) => {}
+                None => write_fc_entry(&mut buffer, Some(entry_nr), event, *exit, full_timestamp)?,
             }
         }
-
         buffer.flush()?;
 
         Ok(())
     }
 }
 
+/// Write a command as `fc` lists it, or just its text when there is no number
+/// to show it under
+fn write_fc_entry(buffer: &mut impl Write, nr: Option<u64>, event: &Event, exit: Option<(u32, UnixTime)>,
+    full_timestamp: bool) -> Result<(), Error>
+{
+    let text = match &event.payload {
+        Payload::Command { text, .. } => text,
+        _ => return Ok(()),
+    };
+    let nr = match nr {
+        Some(nr) => nr,
+        None => {
+            buffer.write(text.as_bytes())?;
+            buffer.write("\n".as_bytes())?;
+            return Ok(());
+        }
+    };
+
+    use termion::color;
+    buffer.write(&format!("{} ", color::Fg(color::Rgb(60, 60, 60))).as_bytes())?;
+    buffer.write(&format!("{:width$}  ", nr, width=6).as_bytes())?;
+    use chrono::prelude::*;
+    let naive = NaiveDateTime::from_timestamp(event.timestamp as i64, 0);
+    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
+    let converted: DateTime<Local> = DateTime::from(datetime);
+
+    buffer.write(&format!("{}{} ",
+            color::Fg(color::Rgb(100, 100, 100)),
+            converted.format("%d.%m.%y")).as_bytes())?;
+
+    if full_timestamp {
+        buffer.write(&format!("{}{} ",
+                color::Fg(color::Rgb(100, 100, 100)),
+                converted.format("%H:%M:%S")).as_bytes())?;
+    }
+
+    if let Some(duration) = run_time(event, exit) {
+        buffer.write(&format!("{}{:>4} ",
+                color::Fg(color::Rgb(100, 100, 100)),
+                format_duration(duration)).as_bytes())?;
+    } else {
+        buffer.write("     ".as_bytes())?;
+    }
+
+    if let Some((exitcode, _timestamp)) = exit {
+        if exitcode == 0 {
+            buffer.write(&format!("{}  ", color::Fg(color::Reset)).as_bytes())?;
+        } else {
+            buffer.write(&format!("{}x{} ",
+                    color::Fg(color::Rgb(255, 0, 0)),
+                    color::Fg(color::Reset)).as_bytes())?;
+        }
+        buffer.write(&format!("{} ", color::Fg(color::Rgb(240, 240, 240))).as_bytes())?;
+    } else {
+        buffer.write(&format!("{}   ", color::Fg(color::Rgb(170, 170, 170))).as_bytes())?;
+    }
+    buffer.write(text.replace("\n", "\\n").as_bytes())?;
+    buffer.write("\n".as_bytes())?;
+
+    Ok(())
+}
+
 fn sub_main() -> Result<(), Error> {
     let opt = Opt::from_args();
-    let superhist = SuperHist::new(opt.root);
+    let superhist = SuperHist::new(opt.root)?;
 
     match opt.command {
         Command::Archive => {
             superhist.archive()?;
         },
-        Command::Import { hist_file } => {
-            superhist.import(&hist_file)?;
+        Command::Reindex => {
+            superhist.reindex()?;
+        },
+        Command::Migrate => {
+            superhist.migrate()?;
+        },
+        Command::Keygen => {
+            superhist.keygen()?;
+        },
+        Command::Fsck { quarantine, repair } => {
+            superhist.fsck(quarantine, repair)?;
+        },
+        Command::Export { format, filter, exit_filter, output } => {
+            superhist.export(format, &EventFilter::new(&filter)?, &exit_filter, &output)?;
+        },
+        Command::Pick { mut filter, exit_filter, rank, global, query, print_candidates } => {
+            let scope = EventFilter::new(&filter)?;
+            let workdir = filter.workdir.take();
+            superhist.pick(&EventFilter::new(&filter)?, &scope, &exit_filter, &rank, workdir, global, query,
+                print_candidates)?;
+        },
+        Command::Merge { host, other_root } => {
+            superhist.merge(&other_root, host)?;
+        },
+        Command::Purge { filter, dry_run } => {
+            superhist.purge(&EventFilter::new(&filter)?, dry_run)?;
+        },
+        Command::Redact { dry_run } => {
+            superhist.redact(dry_run)?;
+        },
+        Command::Stats { filter, exit_filter, limit, json } => {
+            superhist.stats(&EventFilter::new(&filter)?, &exit_filter, limit, json)?;
+        },
+        Command::Session { host, terminal, limit, id } => {
+            match id {
+                Some(id) => superhist.replay_session(&host, &id)?,
+                None => superhist.sessions(&host, &terminal, limit)?,
+            }
+        },
+        Command::Recanonicalize { dry_run } => {
+            superhist.recanonicalize(dry_run)?;
+        },
+        Command::Search { regex, ignore_case, workdir, limit, pattern } => {
+            superhist.search(&pattern, regex, ignore_case, &workdir, limit)?;
+        },
+        Command::Import { hist_file, format, source } => {
+            superhist.import(&hist_file, format, source)?;
         },
-        Command::FC { workdir, start_nr, fetch, start_time } => {
-            superhist.fc(&workdir, start_nr, fetch, start_time)?;
+        Command::FC { filter, exit_filter, start_nr, fetch, start_time, rank } => {
+            superhist.fc(&EventFilter::new(&filter)?, &exit_filter, &rank, start_nr, fetch, start_time)?;
         },
         Command::Add { timestamp, idx, terminal, command, workdir, exit_code, start } => {
             let event = Event {
                 timestamp,
                 idx,
                 terminal,
+                host: None,
                 payload: match (command, workdir, exit_code, start) {
                     (Some(text), Some(workdir), None, false) => {
                         Payload::Command {
                             text,
                             workdir,
+                            duration: None,
+                            physical_workdir: None,
                         }
                     }
                     (None, None, Some(exit_code), false) => {
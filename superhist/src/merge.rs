@@ -0,0 +1,299 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::crypt::seal_stored_line;
+use crate::{blocks, Error, Event, Payload, Segment, SuperHist, UnixTime};
+
+/// Length of the timestamp prefix of archive names, "%F-%H-%M-%S-"
+const ARCHIVE_STAMP_LEN: usize = 20;
+
+pub fn local_host() -> Result<String, Error> {
+    Ok(hostname::get()?.to_string_lossy().into_owned())
+}
+
+/// Events are the same if they come from the same host, terminal and command
+/// index at the same time. The kind of event is part of it, because a command
+/// and its exit code may be recorded within the same second.
+type EventKey = (String, String, u64, UnixTime, u8);
+
+fn kind_rank(payload: &Payload) -> u8 {
+    match payload {
+        Payload::Start => 0,
+        Payload::Command { .. } => 1,
+        Payload::ExitCode(_) => 2,
+        Payload::Unknown => 3,
+    }
+}
+
+fn event_key(event: &Event, default_host: &str) -> EventKey {
+    (event.host.clone().unwrap_or_else(|| default_host.to_owned()), event.terminal.clone(),
+        event.idx, event.timestamp, kind_rank(&event.payload))
+}
+
+impl SuperHist {
+    fn host_file(&self) -> PathBuf {
+        self.root.join("host")
+    }
+
+    /// The host of the events of this root that do not name one, as given
+    /// when it was first merged into another root
+    fn recorded_host(&self) -> Result<Option<String>, Error> {
+        match std::fs::read_to_string(self.host_file()) {
+            Ok(host) => Ok(Some(host.trim().to_owned())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The host a root's archives were written on, if they all agree
+    fn archives_host(&self) -> Result<Option<String>, Error> {
+        let mut hosts = HashSet::new();
+        for segment in self.segments()? {
+            if let Segment::Archived(path) = segment {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let stem = name.trim_end_matches(".xzb").trim_end_matches(".xz");
+                if stem.len() > ARCHIVE_STAMP_LEN {
+                    hosts.insert(stem[ARCHIVE_STAMP_LEN ..].to_owned());
+                }
+            }
+        }
+
+        if hosts.len() == 1 {
+            Ok(hosts.into_iter().next())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The events of a segment along with their stored lines, newest first,
+    /// and the lines that are not events
+    fn segment_events(&self, segment: &Segment) -> Result<(Vec<(Event, String)>, Vec<String>), Error> {
+        let (mut lines, damages) = self.segment_lines(segment)?;
+        self.warn_damages(segment, &damages);
+        if let Segment::Live = segment {
+            lines.reverse();
+        }
+
+        let mut events = vec![];
+        let mut bad_lines = vec![];
+        for line in lines {
+            match serde_json::de::from_str::<Event>(&line) {
+                Ok(event) => events.push((event, line)),
+                Err(_) => bad_lines.push(line),
+            }
+        }
+        Ok((events, bad_lines))
+    }
+
+    /// Take in the history of another root, dropping events we already have.
+    /// The new events are stored as they are, and the local segments from the
+    /// time span they cover are rewritten along with them into one archive,
+    /// so that `fc` reads them all in the order of their time. Events of the
+    /// other root that do not name their host are attributed to `host`, or
+    /// to the host the root was first merged under, or to the host its
+    /// archives were written on. That host is then recorded in the root.
+    pub(crate) fn merge(&self, other_root: &PathBuf, host: Option<String>) -> Result<(), Error> {
+        let other = SuperHist::new(other_root.clone())?;
+
+        // Both roots are locked in the order of their paths, so that merges
+        // in opposite directions cannot deadlock
+        let root = std::fs::canonicalize(&self.root)?;
+        let other_root_path = std::fs::canonicalize(other_root)?;
+        if root == other_root_path {
+            return Err(Error::InvalidParams);
+        }
+        let (lock, other_lock) = if root < other_root_path {
+            let lock = self.lock()?;
+            (lock, other.lock()?)
+        } else {
+            let other_lock = other.lock()?;
+            (self.lock()?, other_lock)
+        };
+        self.recover_main_db()?;
+
+        // Events of a root are keyed by the same host wherever they are
+        // merged into, so that merging back and forth adds nothing
+        let recorded_host = other.recorded_host()?;
+        let other_host = match (host, recorded_host.clone()) {
+            (Some(host), Some(recorded)) if host != recorded => {
+                return Err(Error::HostMismatch(other_root.clone(), recorded));
+            }
+            (Some(host), _) => host,
+            (None, Some(recorded)) => recorded,
+            (None, None) => other.archives_host()?.ok_or_else(|| Error::UnknownHost(other_root.clone()))?,
+        };
+        if recorded_host.is_none() {
+            std::fs::write(other.host_file(), format!("{}\n", other_host))?;
+        }
+        let local_host = match self.recorded_host()? {
+            Some(host) => host,
+            None => local_host()?,
+        };
+
+        let mut seen : HashSet<EventKey> = HashSet::new();
+        for segment in self.segments()? {
+            self.read_segment(&segment, |event| {
+                seen.insert(event_key(&event, &local_host));
+                Ok(true)
+            })?;
+        }
+
+        let mut added = vec![];
+        for segment in other.segments()? {
+            for (mut event, line) in other.segment_events(&segment)?.0 {
+                if !seen.insert(event_key(&event, &other_host)) {
+                    continue;
+                }
+                let line = match event.host {
+                    Some(_) => line,
+                    None => {
+                        event.host = Some(other_host.clone());
+                        line_with_host(&line, &event, &other_host)?
+                    }
+                };
+                added.push((event, line));
+            }
+        }
+        other_lock.unlock()?;
+
+        if added.is_empty() {
+            println!("{}: nothing new", other_root.display());
+            lock.unlock()?;
+            return Ok(());
+        }
+        let count = added.len();
+
+        // The local segments to rewrite are those overlapping the time span
+        // of the new events, growing it as they are taken. db.json is read
+        // ahead of all archives, so its span lasts until now.
+        let mut span = added.iter().fold((UnixTime::MAX, 0), |(min, max), (event, _)| {
+            (min.min(event.timestamp), max.max(event.timestamp))
+        });
+        let mut segments = vec![];
+        for segment in self.segments()? {
+            let index = self.segment_index(&segment)?;
+            let segment_span = match (index.min_timestamp, index.max_timestamp, &segment) {
+                (Some(min), Some(_), Segment::Live) => Some((min, UnixTime::MAX)),
+                (Some(min), Some(max), _) => Some((min, max)),
+                _ => None,
+            };
+            segments.push((segment, segment_span, false));
+        }
+        loop {
+            let mut grown = false;
+            for (_, segment_span, folded) in segments.iter_mut() {
+                if let Some((min, max)) = *segment_span {
+                    if !*folded && min <= span.1 && max >= span.0 {
+                        *folded = true;
+                        span = (span.0.min(min), span.1.max(max));
+                        grown = true;
+                    }
+                }
+            }
+            if !grown {
+                break;
+            }
+        }
+
+        // Archive names only tell how archives sort, so the new one takes
+        // the name of the newest archive it replaces. Failing that, the
+        // next newer archive is taken in too, and when there is none it is
+        // named as `archive` names them.
+        let is_archive = |segment: &Segment| matches!(segment, Segment::Archived(_));
+        let live_folded = segments.iter().any(|(segment, _, folded)| *folded && !is_archive(segment));
+        if !live_folded && !segments.iter().any(|(segment, _, folded)| *folded && is_archive(segment)) {
+            let newer = segments.iter().rposition(|(segment, segment_span, _)| {
+                is_archive(segment) && segment_span.map_or(false, |(min, _)| min > span.1)
+            });
+            if let Some(pos) = newer {
+                segments[pos].2 = true;
+            }
+        }
+        let newest_folded = segments.iter().find(|(segment, _, folded)| *folded && is_archive(segment));
+        let archive = match newest_folded {
+            Some((Segment::Archived(path), _, _)) if !live_folded => path.with_extension("xzb"),
+            _ => {
+                let archive = self.archive_file()?;
+                // Written within the same second, so it is the newest archive
+                for (segment, _, folded) in segments.iter_mut() {
+                    if segment.path(self) == archive {
+                        *folded = true;
+                    }
+                }
+                archive
+            }
+        };
+
+        let cipher = self.cipher()?;
+        let mut events = added;
+        let mut folded_segments = vec![];
+        for (segment, _, folded) in segments.into_iter() {
+            if !folded {
+                continue;
+            }
+            let (local, bad_lines) = self.segment_events(&segment)?;
+            if !bad_lines.is_empty() {
+                let name = segment.path(self).file_name().unwrap().to_string_lossy().into_owned();
+                let mut file = BufWriter::new(File::create(self.quarantine_file(&format!("{}.bad", name))?)?);
+                for line in bad_lines {
+                    writeln!(&mut file, "{}", seal_stored_line(cipher, &line))?;
+                }
+                file.flush()?;
+            }
+            events.extend(local);
+            folded_segments.push(segment);
+        }
+
+        // Newest first, as archives are stored. The sort is stable, so that
+        // events of the same time keep the order they were stored in.
+        events.sort_by(|(a, _), (b, _)| {
+            (b.timestamp, kind_rank(&b.payload)).cmp(&(a.timestamp, kind_rank(&a.payload)))
+        });
+        let (events, lines) : (Vec<Event>, Vec<String>) = events.into_iter().unzip();
+        let events : Vec<Option<Event>> = events.into_iter().map(Some).collect();
+
+        std::fs::create_dir_all(self.archive_dir())?;
+        let tmp_path = PathBuf::from(format!("{}{}", archive.display(), ".tmp"));
+        blocks::write(&tmp_path, &lines, &events, cipher)?;
+        std::fs::rename(&tmp_path, &archive)?;
+        self.index_archived(&archive, &events)?;
+        self.trigrams_archived(&archive, &events)?;
+
+        for segment in folded_segments {
+            match segment {
+                Segment::Live => {
+                    std::fs::remove_file(self.main_db_file())?;
+                    let _ = std::fs::remove_file(self.main_db_index_file());
+                }
+                Segment::Archived(path) if path != archive => {
+                    let segment = Segment::Archived(path);
+                    std::fs::remove_file(segment.path(self))?;
+                    let _ = std::fs::remove_file(self.segment_index_file(&segment));
+                    let _ = std::fs::remove_file(self.segment_trigrams_file(&segment));
+                }
+                Segment::Archived(_) => {}
+            }
+        }
+
+        println!("{}: merged {} events into {}", other_root.display(), count, archive.display());
+
+        lock.unlock()?;
+        Ok(())
+    }
+}
+
+/// The stored line of an event, naming the host it comes from. Fields and
+/// payloads unknown to this version are kept.
+fn line_with_host(line: &str, event: &Event, host: &str) -> Result<String, Error> {
+    let mut record : serde_json::Value = serde_json::de::from_str(line)?;
+    match record.as_object_mut() {
+        Some(fields) if fields.contains_key("v") => {
+            fields.insert("host".to_owned(), host.into());
+            Ok(serde_json::ser::to_string(&record)?)
+        }
+        // Version 1 records have no host field, but are fully known
+        _ => Ok(serde_json::ser::to_string(event)?),
+    }
+}
@@ -0,0 +1,345 @@
+//! Interactive history picker, drawn below the prompt like the procedures
+//! mode. The query is matched fuzzily against the commands, CTRL-R switches
+//! between the commands of the workdir and all of them, and CTRL-S between
+//! ranking them by recency and by frecency.
+
+use std::collections::HashSet;
+use std::io::Write;
+
+use crossterm::event::{self, EventStream, KeyCode, KeyModifiers};
+use crossterm::{style, terminal, QueueableCommand};
+use futures::{FutureExt, StreamExt};
+use unicode_width::UnicodeWidthChar;
+
+use crate::filter::{EventFilter, ExitFilter};
+use crate::rank::{Frecency, RankMode, RankOpts};
+use crate::{term_off, term_on, Error, ExpandingBottomScreen, Payload, SuperHist, Tty};
+use crate::{ALIAS, COMMAND_TEXT, SELECTION_BACKGROUND, SEPARATOR};
+
+static FAILED_MARK : style::Color = style::Color::Rgb { r: 255, g: 0, b: 0 };
+
+/// A command, once per workdir, newest first
+struct Candidate {
+    text: String,
+    in_scope: bool,
+    failed: bool,
+    frecency: f64,
+}
+
+/// Score how well the words of the query match the text, each as a
+/// subsequence. Runs of consecutive characters and matches at the start of
+/// words score higher. The match ignores case unless the query has capitals.
+fn fuzzy_score(text: &str, query: &str) -> Option<i64> {
+    let ignore_case = !query.chars().any(|c| c.is_uppercase());
+    let fold = |c: char| if ignore_case { c.to_lowercase().next().unwrap_or(c) } else { c };
+    let text : Vec<char> = text.chars().map(fold).collect();
+
+    let mut score = 0;
+    for word in query.split_whitespace() {
+        let mut pos = 0;
+        let mut prev : Option<usize> = None;
+        for wanted in word.chars().map(fold) {
+            let found = (pos .. text.len()).find(|&i| text[i] == wanted)?;
+            score += 1;
+            match prev {
+                Some(prev) if found == prev + 1 => score += 4,
+                Some(prev) => score -= std::cmp::min(found - prev - 1, 3) as i64,
+                None => {}
+            }
+            if found == 0 || !text[found - 1].is_alphanumeric() {
+                score += 2;
+            }
+            prev = Some(found);
+            pos = found + 1;
+        }
+    }
+
+    Some(score)
+}
+
+struct Picker {
+    candidates: Vec<Candidate>,
+    has_workdir: bool,
+    global: bool,
+    rank: RankMode,
+    query: String,
+    /// Indices of the matching candidates, best first
+    matches: Vec<usize>,
+    selected: usize,
+    top: usize,
+    chosen: Option<String>,
+    lines: ExpandingBottomScreen,
+}
+
+impl Picker {
+    fn refilter(&mut self) {
+        let mut seen = HashSet::new();
+        let mut scored = vec![];
+        for (index, candidate) in self.candidates.iter().enumerate() {
+            if !self.global && !candidate.in_scope {
+                continue;
+            }
+            if !seen.insert(candidate.text.as_str()) {
+                continue;
+            }
+            if let Some(score) = fuzzy_score(&candidate.text, &self.query) {
+                scored.push((score, index));
+            }
+        }
+
+        // Stable, so that equal matches stay newest first
+        if self.rank == RankMode::Frecency {
+            let candidates = &self.candidates;
+            scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| {
+                candidates[b.1].frecency.partial_cmp(&candidates[a.1].frecency)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }));
+        } else {
+            scored.sort_by(|a, b| b.0.cmp(&a.0));
+        }
+        self.matches = scored.into_iter().map(|(_, index)| index).collect();
+        self.selected = 0;
+        self.top = 0;
+    }
+
+    fn rows(&self, term_size: (u16, u16)) -> usize {
+        std::cmp::max(term_size.1 as usize * 2 / 5, 3) - 2
+    }
+
+    fn select(&mut self, selected: usize, rows: usize) {
+        self.selected = std::cmp::min(selected, self.matches.len().saturating_sub(1));
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + rows {
+            self.top = self.selected + 1 - rows;
+        }
+    }
+
+    /// Handle a key, returning false once done
+    fn key(&mut self, key_event: event::KeyEvent, rows: usize) -> bool {
+        let control = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        match key_event.code {
+            KeyCode::Enter => {
+                self.chosen = self.matches.get(self.selected)
+                    .map(|index| self.candidates[*index].text.clone());
+                return false;
+            }
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') | KeyCode::Char('g') if control => return false,
+            KeyCode::Char('r') if control => {
+                if self.has_workdir {
+                    self.global = !self.global;
+                    self.refilter();
+                }
+            }
+            KeyCode::Tab => {
+                if self.has_workdir {
+                    self.global = !self.global;
+                    self.refilter();
+                }
+            }
+            KeyCode::Char('s') if control => {
+                self.rank = match self.rank {
+                    RankMode::Recency => RankMode::Frecency,
+                    RankMode::Frecency => RankMode::Recency,
+                };
+                self.refilter();
+            }
+            KeyCode::Char('u') if control => {
+                self.query.clear();
+                self.refilter();
+            }
+            KeyCode::Up => self.select(self.selected.saturating_sub(1), rows),
+            KeyCode::Char('p') | KeyCode::Char('k') if control => self.select(self.selected.saturating_sub(1), rows),
+            KeyCode::Down => self.select(self.selected + 1, rows),
+            KeyCode::Char('n') | KeyCode::Char('j') if control => self.select(self.selected + 1, rows),
+            KeyCode::PageUp => self.select(self.selected.saturating_sub(rows), rows),
+            KeyCode::PageDown => self.select(self.selected + rows, rows),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.refilter();
+            }
+            KeyCode::Char(c) if !control => {
+                self.query.push(c);
+                self.refilter();
+            }
+            _ => {}
+        }
+
+        true
+    }
+
+    fn redraw(&mut self, tty: &mut Tty) -> Result<(), Error> {
+        let term_size = terminal::size()?;
+        let width = term_size.0 as usize;
+        let rows = self.rows(term_size);
+
+        self.lines.start(tty, term_size)?;
+        self.lines.set_indent_x(0, tty)?;
+        tty.queue(style::ResetColor)?;
+
+        self.lines.start_line(tty)?;
+        tty.queue(style::SetForegroundColor(ALIAS))?;
+        let scope = if self.global || !self.has_workdir { "global" } else { "workdir" };
+        let rank = match self.rank {
+            RankMode::Recency => "",
+            RankMode::Frecency => ", frecency",
+        };
+        self.lines.print(&format!("[{}{}] ", scope, rank), tty)?;
+        tty.queue(style::SetForegroundColor(COMMAND_TEXT))?;
+        self.lines.print(&format!("> {}", self.query), tty)?;
+        tty.queue(style::SetForegroundColor(SEPARATOR))?;
+        self.lines.print(&format!("  {}", self.matches.len()), tty)?;
+        self.lines.end_line(tty)?;
+
+        self.lines.start_line(tty)?;
+        self.lines.print(&"-".repeat(width), tty)?;
+        self.lines.end_line(tty)?;
+
+        for (row, index) in self.matches.iter().enumerate().skip(self.top).take(rows) {
+            let candidate = &self.candidates[*index];
+
+            self.lines.start_line(tty)?;
+            if row == self.selected {
+                tty.queue(style::SetBackgroundColor(SELECTION_BACKGROUND))?;
+            } else {
+                tty.queue(style::ResetColor)?;
+            }
+            if candidate.failed {
+                tty.queue(style::SetForegroundColor(FAILED_MARK))?;
+                self.lines.print("x ", tty)?;
+            } else {
+                self.lines.print("  ", tty)?;
+            }
+            tty.queue(style::SetForegroundColor(COMMAND_TEXT))?;
+
+            // One row each, cut at the edge of the terminal
+            let mut text = String::new();
+            let mut text_width = 2;
+            for c in candidate.text.replace("\n", "\\n").chars() {
+                text_width += c.width().unwrap_or(1);
+                if text_width >= width {
+                    break;
+                }
+                text.push(c);
+            }
+            self.lines.print(&text, tty)?;
+            self.lines.end_line(tty)?;
+        }
+
+        tty.queue(style::ResetColor)?;
+        self.lines.end(tty)?;
+        tty.flush()?;
+
+        Ok(())
+    }
+
+    async fn run(&mut self, tty: &mut Tty) -> Result<(), Error> {
+        let mut reader = EventStream::new();
+
+        loop {
+            self.redraw(tty)?;
+
+            futures::select! {
+                maybe_event = reader.next().fuse() => {
+                    match maybe_event {
+                        Some(Ok(event::Event::Key(key_event))) => {
+                            let rows = self.rows(terminal::size()?);
+                            if !self.key(key_event, rows) {
+                                break;
+                            }
+                        }
+                        Some(Ok(_)) => {}
+                        Some(Err(_)) => {
+                            break;
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+
+        self.lines.clear(tty)?;
+        tty.flush()?;
+
+        Ok(())
+    }
+}
+
+impl SuperHist {
+    /// Let the user pick a command from the history, and print it. Starts
+    /// with the commands within `scope` of `workdir` unless `global` is set.
+    /// With `print_candidates`, all that match the query are printed instead.
+    pub(crate) fn pick(&self, filter: &EventFilter, scope: &EventFilter, exit_filter: &ExitFilter,
+        rank: &RankOpts, workdir: Option<String>, global: bool, query: String,
+        print_candidates: bool) -> Result<(), Error>
+    {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs()).unwrap_or(0);
+        let mut frecency = Frecency::new(now, rank.rank_workdir.clone().or_else(|| workdir.clone()));
+        let mut seen = HashSet::new();
+        let mut candidates = vec![];
+        self.scan_commands(None, |index| filter.may_match(index), |event, exit| {
+            if !filter.matches(&event) || !exit_filter.matches(&event, exit) {
+                return Ok(true);
+            }
+            frecency.add(&event, exit);
+            let in_scope = scope.workdir_matches(event.payload.physical_workdir().unwrap_or(""));
+            if let Payload::Command { text, workdir, .. } = event.payload {
+                if seen.insert((text.clone(), workdir)) {
+                    let failed = exit.map_or(false, |(code, _)| code != 0);
+                    candidates.push(Candidate { text, in_scope, failed, frecency: 0.0 });
+                }
+            }
+            Ok(true)
+        })?;
+        for candidate in candidates.iter_mut() {
+            candidate.frecency = frecency.score(&candidate.text);
+        }
+
+        let mut picker = Picker {
+            candidates,
+            has_workdir: workdir.is_some(),
+            global,
+            rank: rank.mode,
+            query,
+            matches: vec![],
+            selected: 0,
+            top: 0,
+            chosen: None,
+            lines: Default::default(),
+        };
+        picker.refilter();
+
+        if print_candidates {
+            let mut buffer = std::io::BufWriter::new(std::io::stdout());
+            for index in picker.matches.iter() {
+                writeln!(buffer, "{}", picker.candidates[*index].text.replace("\n", "\\n"))?;
+            }
+            buffer.flush()?;
+            return Ok(());
+        }
+
+        let mut tty = std::fs::OpenOptions::new().write(true).open("/dev/tty")?;
+        term_on(&mut tty)?;
+        tty.queue(style::Print("\r\n"))?;
+
+        let e = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                picker.run(&mut tty).await
+            });
+
+        tty.queue(crossterm::cursor::MoveToPreviousLine(1))?;
+        term_off(&mut tty)?;
+        e?;
+
+        if let Some(text) = picker.chosen {
+            println!("{}", text);
+        }
+
+        Ok(())
+    }
+}
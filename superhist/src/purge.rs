@@ -0,0 +1,120 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::filter::EventFilter;
+use crate::{Error, Event, Payload, SuperHist, UnixTime};
+
+type EventKey = (String, u64);
+
+impl SuperHist {
+    /// Remove the events selected by `filter` from db.json and all archives,
+    /// along with the exit codes of removed commands. With `dry_run`, only
+    /// list what would be removed.
+    pub(crate) fn purge(&self, filter: &EventFilter, dry_run: bool) -> Result<(), Error> {
+        if filter.is_empty() {
+            // Refuse to wipe the whole history by accident
+            return Err(Error::InvalidParams);
+        }
+
+        let lock = self.lock()?;
+        self.recover_main_db()?;
+
+        let mut segments = vec![];
+        for segment in self.segments()? {
+            let (lines, damages) = self.segment_lines(&segment)?;
+            if !damages.is_empty() {
+                eprintln!("superhist: warning - {}: not purging a damaged segment, run `superhist fsck` first",
+                    segment.path(self).display());
+                continue;
+            }
+            segments.push((segment, lines));
+        }
+
+        // The exit code of a command is the first one of the same terminal and
+        // index that follows it
+        let mut commands : HashMap<EventKey, Vec<UnixTime>> = HashMap::new();
+        let mut exits : HashMap<EventKey, Vec<UnixTime>> = HashMap::new();
+        for (_, lines) in segments.iter() {
+            for line in lines.iter() {
+                let event : Event = match serde_json::de::from_str(line) {
+                    Ok(event) => event,
+                    Err(_) => continue,
+                };
+                let key = (event.terminal.clone(), event.idx);
+                match event.payload {
+                    Payload::Command { .. } if filter.matches(&event) => {
+                        commands.entry(key).or_default().push(event.timestamp)
+                    }
+                    Payload::ExitCode(_) => exits.entry(key).or_default().push(event.timestamp),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut paired_exits : HashSet<(EventKey, UnixTime)> = HashSet::new();
+        for (key, started) in commands.into_iter() {
+            if let Some(exit_times) = exits.get(&key) {
+                for started in started {
+                    let exit_time = exit_times.iter().filter(|t| **t >= started).min();
+                    if let Some(exit_time) = exit_time {
+                        paired_exits.insert((key.clone(), *exit_time));
+                    }
+                }
+            }
+        }
+
+        let mut total = 0;
+        for (segment, lines) in segments.iter() {
+            let path = segment.path(self);
+            let mut kept = vec![];
+            let mut removed = 0;
+
+            for line in lines.iter() {
+                let event : Event = match serde_json::de::from_str(line) {
+                    Ok(event) => event,
+                    Err(_) => {
+                        kept.push(line.clone());
+                        continue;
+                    }
+                };
+
+                let remove = filter.matches(&event) || match event.payload {
+                    Payload::ExitCode(_) => paired_exits.contains(
+                        &((event.terminal.clone(), event.idx), event.timestamp)),
+                    _ => false,
+                };
+                if !remove {
+                    kept.push(line.clone());
+                    continue;
+                }
+
+                removed += 1;
+                if dry_run {
+                    let what = match &event.payload {
+                        Payload::Start => "start".to_owned(),
+                        Payload::Command { text, workdir, .. } => format!("{} {}", workdir, text.replace("\n", "\\n")),
+                        Payload::ExitCode(code) => format!("exit {}", code),
+                        Payload::Unknown => "unknown".to_owned(),
+                    };
+                    println!("{}: {} {} {}", path.display(), event.timestamp, event.terminal, what);
+                }
+            }
+
+            if removed == 0 {
+                continue;
+            }
+
+            total += removed;
+            if !dry_run {
+                self.write_segment_lines(segment, &kept)?;
+                println!("{}: removed {} events", path.display(), removed);
+            }
+        }
+
+        if dry_run {
+            println!("would remove {} events", total);
+        }
+
+        lock.unlock()?;
+        Ok(())
+    }
+}
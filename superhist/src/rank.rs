@@ -0,0 +1,104 @@
+//! Ranking of commands by frecency: each use of a command counts for less
+//! the older it is, halving every week, and for more when it was in the
+//! directory of interest. The sum is scaled by how often the command
+//! succeeded.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use structopt::StructOpt;
+
+use crate::filter::physical_dir;
+use crate::{Event, Payload, UnixTime};
+
+const HALF_LIFE_SECS: f64 = 7.0 * 24.0 * 3600.0;
+
+/// Extra weight of uses in the directory of interest
+const WORKDIR_BOOST: f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RankMode {
+    /// Newest first
+    Recency,
+    Frecency,
+}
+
+impl FromStr for RankMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "recency" => Ok(RankMode::Recency),
+            "frecency" => Ok(RankMode::Frecency),
+            _ => Err(format!("unknown ranking {:?}", s)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct RankOpts {
+    /// Order of commands, recency or frecency
+    #[structopt(long = "rank", default_value = "recency")]
+    pub mode: RankMode,
+
+    /// Favor commands used in this directory when ranking by frecency
+    #[structopt(long = "rank-workdir")]
+    pub rank_workdir: Option<String>,
+}
+
+#[derive(Default)]
+struct Usage {
+    weight: f64,
+    succeeded: u64,
+    failed: u64,
+}
+
+pub struct Frecency {
+    now: UnixTime,
+    workdir: Option<String>,
+    usages: HashMap<String, Usage>,
+}
+
+impl Frecency {
+    pub fn new(now: UnixTime, workdir: Option<String>) -> Self {
+        let workdir = workdir.map(|workdir| physical_dir(&workdir).unwrap_or(workdir));
+        Frecency { now, workdir, usages: HashMap::new() }
+    }
+
+    /// Account for a use of a command. Returns whether it is the first use
+    /// seen of that command text.
+    pub fn add(&mut self, event: &Event, exit: Option<(u32, UnixTime)>) -> bool {
+        let text = match &event.payload {
+            Payload::Command { text, .. } => text,
+            _ => return false,
+        };
+
+        let age = self.now.saturating_sub(event.timestamp) as f64;
+        let mut weight = 0.5f64.powf(age / HALF_LIFE_SECS);
+        if self.workdir.as_deref() == event.payload.physical_workdir() {
+            weight *= 1.0 + WORKDIR_BOOST;
+        }
+
+        let first = !self.usages.contains_key(text);
+        let usage = self.usages.entry(text.clone()).or_default();
+        usage.weight += weight;
+        match exit {
+            Some((0, _)) => usage.succeeded += 1,
+            Some(_) => usage.failed += 1,
+            None => {}
+        }
+
+        first
+    }
+
+    pub fn score(&self, text: &str) -> f64 {
+        match self.usages.get(text) {
+            Some(usage) => {
+                // Commands never seen exiting count as half successful
+                let success = (usage.succeeded as f64 + 1.0) / ((usage.succeeded + usage.failed) as f64 + 2.0);
+                usage.weight * (0.5 + success)
+            }
+            None => 0.0,
+        }
+    }
+}
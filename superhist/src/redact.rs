@@ -0,0 +1,158 @@
+use std::borrow::Cow;
+
+use regex::{Captures, Regex};
+
+use crate::config::Redaction;
+use crate::{Error, Event, Payload, SuperHist};
+
+/// Common shapes of secrets. Where a pattern has a `secret` group, only that
+/// part of the match is replaced.
+const BUILTIN_PATTERNS: &[&str] = &[
+    // Private keys pasted whole
+    r"(?s)-----BEGIN [A-Z ]*PRIVATE KEY-----.*?(-----END [A-Z ]*PRIVATE KEY-----|$)",
+    // AWS access key IDs
+    r"\b(AKIA|ASIA)[0-9A-Z]{16}\b",
+    // GitHub tokens
+    r"\bgh[pousr]_[A-Za-z0-9]{36,}\b",
+    r"\bgithub_pat_[A-Za-z0-9_]{22,}",
+    // Slack tokens
+    r"\bxox[abprs]-[A-Za-z0-9-]{10,}",
+    // JSON web tokens
+    r"\beyJ[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,}",
+    // Authorization headers
+    r#"(?i)\bauthorization:\s*(bearer|basic|token)\s+(?P<secret>[^\s'"]+)"#,
+    // Passwords in URLs
+    r"://[^/\s:@]+:(?P<secret>[^/\s@]+)@",
+    // Assignments to variables whose name ends in a secret-like word, such
+    // as API_TOKEN=..., and options such as --password ...
+    r#"(?i)\b(?:[a-z0-9]+_)*(password|passwd|secret|token|(api|access)_?key)=(?P<secret>[^\s'"]+)"#,
+    r#"(?i)--(password|passwd|secret|token|api-?key)[= ](?P<secret>[^\s'"]+)"#,
+];
+
+pub struct Redactor {
+    patterns: Vec<Regex>,
+    placeholder: String,
+}
+
+impl Redactor {
+    pub fn new(config: &Redaction) -> Result<Self, Error> {
+        let mut patterns = vec![];
+        if config.builtin {
+            for pattern in BUILTIN_PATTERNS {
+                patterns.push(Regex::new(pattern)?);
+            }
+        }
+        for pattern in config.patterns.iter() {
+            patterns.push(Regex::new(pattern)?);
+        }
+
+        Ok(Redactor {
+            patterns,
+            placeholder: config.placeholder.clone(),
+        })
+    }
+
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+
+        for pattern in self.patterns.iter() {
+            if !pattern.is_match(&text) {
+                continue;
+            }
+
+            let replaced = pattern.replace_all(&text, |captures: &Captures| {
+                let all = captures.get(0).unwrap();
+                match captures.name("secret") {
+                    Some(secret) => format!("{}{}{}",
+                        &all.as_str()[.. secret.start() - all.start()],
+                        self.placeholder,
+                        &all.as_str()[secret.end() - all.start() ..]),
+                    None => self.placeholder.clone(),
+                }
+            }).into_owned();
+            text = Cow::Owned(replaced);
+        }
+
+        text
+    }
+
+    /// Redact the command text of an event. Returns whether it changed.
+    pub fn redact_event(&self, event: &mut Event) -> bool {
+        if let Payload::Command { text, .. } = &mut event.payload {
+            if let Cow::Owned(redacted) = self.redact(text) {
+                if &redacted != text {
+                    *text = redacted;
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
+impl SuperHist {
+    pub(crate) fn redactor(&self) -> Result<Option<Redactor>, Error> {
+        let config = &self.config.redaction;
+        if !config.builtin && config.patterns.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Redactor::new(config)?))
+    }
+
+    /// Apply the redaction settings to db.json and all archives. With
+    /// `dry_run`, only list the commands that would change.
+    pub(crate) fn redact(&self, dry_run: bool) -> Result<(), Error> {
+        let redactor = match self.redactor()? {
+            Some(redactor) => redactor,
+            None => return Ok(()),
+        };
+
+        let lock = self.lock()?;
+        self.recover_main_db()?;
+
+        for segment in self.segments()? {
+            let path = segment.path(self);
+            let (lines, damages) = self.segment_lines(&segment)?;
+            if !damages.is_empty() {
+                eprintln!("superhist: warning - {}: not redacting a damaged segment, run `superhist fsck` first",
+                    path.display());
+                continue;
+            }
+
+            let mut redacted = 0;
+            let mut new_lines = Vec::with_capacity(lines.len());
+            for line in lines.into_iter() {
+                let mut event : Event = match serde_json::de::from_str(&line) {
+                    Ok(event) => event,
+                    Err(_) => {
+                        new_lines.push(line);
+                        continue;
+                    }
+                };
+
+                if !redactor.redact_event(&mut event) {
+                    new_lines.push(line);
+                    continue;
+                }
+
+                redacted += 1;
+                if dry_run {
+                    if let Payload::Command { text, .. } = &event.payload {
+                        println!("{}: {} {}", path.display(), event.timestamp, text.replace("\n", "\\n"));
+                    }
+                }
+                new_lines.push(serde_json::ser::to_string(&event)?);
+            }
+
+            if redacted > 0 && !dry_run {
+                self.write_segment_lines(&segment, &new_lines)?;
+                println!("{}: redacted {} commands", path.display(), redacted);
+            }
+        }
+
+        lock.unlock()?;
+        Ok(())
+    }
+}
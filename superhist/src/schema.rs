@@ -0,0 +1,213 @@
+//! On-disk layout of history records.
+//!
+//! Version 1 records carry no version marker and use serde's externally
+//! tagged enum layout for the payload:
+//!
+//!     {"timestamp":1,"idx":1,"terminal":"/dev/pts/1","payload":{"ExitCode":0}}
+//!
+//! Starting with version 2 every record has a `v` field and the payload is
+//! tagged by a `type` field. Readers ignore fields they do not know, and
+//! payload types they do not know are read as `Payload::Unknown`, so newer
+//! writers can add both without breaking older readers.
+//!
+//!     {"v":2,"timestamp":1,"idx":1,"terminal":"/dev/pts/1","payload":{"type":"exit_code","code":0}}
+//!
+//! Records merged from another machine also have a `host` field, and
+//! commands imported with their run time but no exit code have a `duration`
+//! in seconds. Commands whose workdir was reached through symlinks have the
+//! resolved path as `physical_workdir`.
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+use crate::{Error, Event, Payload, SuperHist, UnixTime};
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Deserialize)]
+enum PayloadV1 {
+    Start,
+    Command {
+        text: String,
+        workdir: String,
+    },
+    ExitCode(u32),
+}
+
+#[derive(Deserialize)]
+struct RecordV1 {
+    timestamp: UnixTime,
+    idx: u64,
+    terminal: String,
+    payload: PayloadV1,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PayloadV2<'a> {
+    Start,
+    Command {
+        #[serde(borrow)]
+        text: std::borrow::Cow<'a, str>,
+        #[serde(borrow)]
+        workdir: std::borrow::Cow<'a, str>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u64>,
+        #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+        physical_workdir: Option<std::borrow::Cow<'a, str>>,
+    },
+    ExitCode {
+        code: u32,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordV2<'a> {
+    v: u32,
+    timestamp: UnixTime,
+    idx: u64,
+    #[serde(borrow)]
+    terminal: std::borrow::Cow<'a, str>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    host: Option<std::borrow::Cow<'a, str>>,
+    #[serde(borrow)]
+    payload: PayloadV2<'a>,
+}
+
+/// A record of any known schema version, as read from disk
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyRecord<'a> {
+    #[serde(borrow)]
+    V2(RecordV2<'a>),
+    V1(RecordV1),
+}
+
+impl<'a> AnyRecord<'a> {
+    pub fn version(&self) -> u32 {
+        match self {
+            AnyRecord::V2(record) => record.v,
+            AnyRecord::V1(_) => 1,
+        }
+    }
+}
+
+impl<'a> From<AnyRecord<'a>> for Event {
+    fn from(record: AnyRecord<'a>) -> Event {
+        match record {
+            AnyRecord::V1(record) => Event {
+                timestamp: record.timestamp,
+                idx: record.idx,
+                terminal: record.terminal,
+                host: None,
+                payload: match record.payload {
+                    PayloadV1::Start => Payload::Start,
+                    PayloadV1::Command { text, workdir } => Payload::Command {
+                        text,
+                        workdir,
+                        duration: None,
+                        physical_workdir: None,
+                    },
+                    PayloadV1::ExitCode(code) => Payload::ExitCode(code),
+                },
+            },
+            AnyRecord::V2(record) => Event {
+                timestamp: record.timestamp,
+                idx: record.idx,
+                terminal: record.terminal.into_owned(),
+                host: record.host.map(|host| host.into_owned()),
+                payload: match record.payload {
+                    PayloadV2::Start => Payload::Start,
+                    PayloadV2::Command { text, workdir, duration, physical_workdir } => Payload::Command {
+                        text: text.into_owned(),
+                        workdir: workdir.into_owned(),
+                        duration,
+                        physical_workdir: physical_workdir.map(|workdir| workdir.into_owned()),
+                    },
+                    PayloadV2::ExitCode { code } => Payload::ExitCode(code),
+                    PayloadV2::Unknown => Payload::Unknown,
+                },
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Event {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        AnyRecord::deserialize(deserializer).map(Event::from)
+    }
+}
+
+impl Serialize for Event {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let payload = match &self.payload {
+            Payload::Start => PayloadV2::Start,
+            Payload::Command { text, workdir, duration, physical_workdir } => PayloadV2::Command {
+                text: text.as_str().into(),
+                workdir: workdir.as_str().into(),
+                duration: *duration,
+                physical_workdir: physical_workdir.as_deref().map(|workdir| workdir.into()),
+            },
+            Payload::ExitCode(code) => PayloadV2::ExitCode { code: *code },
+            Payload::Unknown => PayloadV2::Unknown,
+        };
+
+        RecordV2 {
+            v: SCHEMA_VERSION,
+            timestamp: self.timestamp,
+            idx: self.idx,
+            terminal: self.terminal.as_str().into(),
+            host: self.host.as_deref().map(|host| host.into()),
+            payload,
+        }.serialize(serializer)
+    }
+}
+
+/// Bring a stored line to the current schema. Returns None if the line is
+/// already current, or is from a newer version that must be kept as is.
+pub fn upgrade_line(line: &str) -> Result<Option<String>, serde_json::Error> {
+    let record : AnyRecord = serde_json::de::from_str(line)?;
+    if record.version() >= SCHEMA_VERSION {
+        return Ok(None);
+    }
+
+    let event = Event::from(record);
+    Ok(Some(serde_json::ser::to_string(&event)?))
+}
+
+impl SuperHist {
+    /// Rewrite db.json and the archives in the current schema. Records that
+    /// are current, newer or unreadable are kept as they are.
+    pub(crate) fn migrate(&self) -> Result<(), Error> {
+        let lock = self.lock()?;
+
+        for segment in self.segments()? {
+            let (lines, damages) = self.segment_lines(&segment)?;
+            if !damages.is_empty() {
+                eprintln!("superhist: warning - {}: not migrating a damaged segment, run `superhist fsck` first",
+                    segment.path(self).display());
+                continue;
+            }
+
+            let mut upgraded = 0;
+            let lines : Vec<String> = lines.into_iter()
+                .map(|line| match upgrade_line(&line) {
+                    Ok(Some(new_line)) => {
+                        upgraded += 1;
+                        new_line
+                    }
+                    _ => line,
+                })
+                .collect();
+
+            if upgraded > 0 {
+                self.write_segment_lines(&segment, &lines)?;
+                println!("{}: upgraded {} records", segment.path(self).display(), upgraded);
+            }
+        }
+
+        lock.unlock()?;
+        Ok(())
+    }
+}
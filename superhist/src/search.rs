@@ -0,0 +1,285 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::filter::physical_dir;
+use crate::{Error, Event, Payload, Segment, SuperHist};
+
+const TRIGRAMS_MAGIC: &str = "superhist-trigrams 1";
+
+pub type Trigram = [u8; 3];
+
+/// Sorted set of the trigrams appearing in the commands of one segment. A
+/// query can only match in a segment holding all of the query's trigrams.
+pub struct TrigramSet {
+    trigrams: Vec<Trigram>,
+}
+
+impl TrigramSet {
+    fn from_events<'a>(events: impl Iterator<Item=&'a Event>) -> Self {
+        let mut set = BTreeSet::new();
+        for event in events {
+            if let Payload::Command { text, .. } = &event.payload {
+                set.extend(trigrams_of(&text.to_lowercase()));
+            }
+        }
+
+        TrigramSet {
+            trigrams: set.into_iter().collect(),
+        }
+    }
+
+    pub fn contains_all(&self, trigrams: &[Trigram]) -> bool {
+        trigrams.iter().all(|t| self.trigrams.binary_search(t).is_ok())
+    }
+}
+
+fn trigrams_of(s: &str) -> impl Iterator<Item=Trigram> + '_ {
+    s.as_bytes().windows(3).map(|w| [w[0], w[1], w[2]])
+}
+
+/// Literal runs that any match of `pattern` must contain. Conservative: when
+/// in doubt, a part of the pattern contributes nothing, and escapes or flags
+/// not understood here leave no literals at all.
+fn required_literals(pattern: &str) -> Vec<String> {
+    let chars: Vec<char> = pattern.chars().collect();
+    if chars.contains(&'|') {
+        return vec![];
+    }
+
+    let mut literals = vec![];
+    let mut run = String::new();
+    let mut depth = 0;
+    let mut i = 0;
+
+    let mut finish = |run: &mut String| {
+        if run.chars().count() >= 3 {
+            literals.push(run.to_lowercase());
+        }
+        run.clear();
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' => {
+                match chars.get(i + 1) {
+                    Some(e) if !e.is_ascii_alphanumeric() => {
+                        if depth == 0 {
+                            run.push(*e);
+                        }
+                    }
+                    // Classes, assertions and control characters, none of
+                    // which is part of a literal
+                    Some('d' | 'D' | 'w' | 'W' | 's' | 'S' | 'b' | 'B' | 'A' | 'z' |
+                        'n' | 'r' | 't' | 'f' | 'v') => finish(&mut run),
+                    _ => return vec![],
+                }
+                i += 1;
+            }
+            '?' | '*' | '{' => {
+                // The previous item may be absent
+                run.pop();
+                finish(&mut run);
+                if c == '{' {
+                    while i < chars.len() && chars[i] != '}' {
+                        i += 1;
+                    }
+                }
+            }
+            '+' => finish(&mut run),
+            '(' => {
+                // Groups that set flags, such as (?x), change what the rest
+                // of the pattern means
+                if chars.get(i + 1) == Some(&'?') && !matches!(chars.get(i + 2), Some(':') | Some('P')) {
+                    return vec![];
+                }
+                finish(&mut run);
+                depth += 1;
+            }
+            ')' => {
+                finish(&mut run);
+                depth -= 1;
+            }
+            '[' => {
+                finish(&mut run);
+                i += 1;
+                if chars.get(i) == Some(&']') {
+                    i += 1;
+                }
+                while i < chars.len() && chars[i] != ']' {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            '.' | '^' | '$' => finish(&mut run),
+            c if depth == 0 => run.push(c),
+            _ => {}
+        }
+        i += 1;
+    }
+    finish(&mut run);
+
+    literals
+}
+
+impl SuperHist {
+    pub(crate) fn segment_trigrams_file(&self, segment: &Segment) -> PathBuf {
+        let path = segment.path(self);
+        PathBuf::from(format!("{}{}", path.display(), ".tri"))
+    }
+
+    fn load_segment_trigrams(&self, segment: &Segment) -> Option<TrigramSet> {
+        let source_len = std::fs::metadata(segment.path(self)).ok()?.len();
+        let mut reader = BufReader::new(File::open(self.segment_trigrams_file(segment)).ok()?);
+
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        let plain_header = format!("{} {}", TRIGRAMS_MAGIC, source_len);
+        let sealed = if header == plain_header {
+            false
+        } else if header == format!("{} sealed", plain_header) {
+            true
+        } else {
+            return None;
+        };
+
+        let mut data = vec![];
+        reader.read_to_end(&mut data).ok()?;
+        if sealed {
+            data = self.cipher().ok()??.open(&data).ok()?;
+        }
+        let trigrams = data.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+        Some(TrigramSet { trigrams })
+    }
+
+    fn save_segment_trigrams(&self, segment: &Segment, set: &TrigramSet) -> Result<(), Error> {
+        let source_len = std::fs::metadata(segment.path(self))?.len();
+        let path = self.segment_trigrams_file(segment);
+        let tmp_path = PathBuf::from(format!("{}{}", path.display(), ".tmp"));
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+        let mut file = BufWriter::new(file);
+        let data = set.trigrams.concat();
+        match self.cipher()? {
+            Some(cipher) => {
+                writeln!(&mut file, "{} {} sealed", TRIGRAMS_MAGIC, source_len)?;
+                file.write_all(&cipher.seal(&data))?;
+            }
+            None => {
+                writeln!(&mut file, "{} {}", TRIGRAMS_MAGIC, source_len)?;
+                file.write_all(&data)?;
+            }
+        }
+        file.flush()?;
+        drop(file);
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn build_segment_trigrams(&self, segment: &Segment) -> Result<TrigramSet, Error> {
+        let mut events = vec![];
+        self.read_segment(segment, |event| {
+            events.push(event);
+            Ok(true)
+        })?;
+        Ok(TrigramSet::from_events(events.iter()))
+    }
+
+    /// Get the trigrams of an archived segment, rebuilding them if needed
+    pub(crate) fn segment_trigrams(&self, segment: &Segment) -> Result<TrigramSet, Error> {
+        if let Some(set) = self.load_segment_trigrams(segment) {
+            return Ok(set);
+        }
+
+        let set = self.build_segment_trigrams(segment)?;
+        self.save_segment_trigrams(segment, &set)?;
+        Ok(set)
+    }
+
+    /// Write the trigrams of a freshly written archive segment
+    pub(crate) fn trigrams_archived(&self, path: &Path, events: &[Option<Event>]) -> Result<(), Error> {
+        let set = TrigramSet::from_events(events.iter().flatten());
+        self.save_segment_trigrams(&Segment::Archived(path.to_owned()), &set)
+    }
+
+    pub(crate) fn retrigram(&self, segment: &Segment) -> Result<(), Error> {
+        let set = self.build_segment_trigrams(segment)?;
+        self.save_segment_trigrams(segment, &set)
+    }
+
+    /// Print commands matching a substring or a regex, newest first
+    pub(crate) fn search(&self, pattern: &str, is_regex: bool, ignore_case: bool,
+        workdir: &Option<String>, limit: Option<usize>) -> Result<(), Error>
+    {
+        let (expr, literals) = if is_regex {
+            (pattern.to_owned(), required_literals(pattern))
+        } else {
+            (regex::escape(pattern), vec![pattern.to_lowercase()])
+        };
+        let expr = if ignore_case { format!("(?i){}", expr) } else { expr };
+        let re = Regex::new(&expr)?;
+        let limit = limit.filter(|limit| *limit > 0);
+        let workdir = &workdir.as_ref().map(|workdir| physical_dir(workdir).unwrap_or_else(|| workdir.clone()));
+
+        let mut required = vec![];
+        for literal in literals.iter() {
+            required.extend(trigrams_of(literal));
+        }
+
+        let mut buffer = BufWriter::with_capacity(0x10000, std::io::stdout());
+        let mut seen = HashSet::new();
+        let mut found = 0;
+
+        let mut lock = Some(self.lock()?);
+
+        for segment in self.segments()? {
+            let skip = match segment {
+                Segment::Live => false,
+                Segment::Archived(_) => {
+                    let index = self.segment_index(&segment)?;
+                    index.commands(workdir) == 0 ||
+                        !self.segment_trigrams(&segment)?.contains_all(&required)
+                }
+            };
+
+            if !skip {
+                self.read_segment(&segment, |event| {
+                    if let Some(workdir) = workdir {
+                        if event.payload.physical_workdir() != Some(workdir.as_str()) {
+                            return Ok(true);
+                        }
+                    }
+                    if let Payload::Command { text, .. } = event.payload {
+                        if re.is_match(&text) && !seen.contains(&text) {
+                            buffer.write_all(text.replace("\n", "\\n").as_bytes())?;
+                            buffer.write_all("\n".as_bytes())?;
+                            seen.insert(text);
+                            found += 1;
+                        }
+                    }
+                    Ok(limit.map_or(true, |limit| found < limit))
+                })?;
+            }
+
+            if let Segment::Live = segment {
+                if let Some(lock) = lock.take() {
+                    lock.unlock()?;
+                }
+            }
+
+            if limit.map_or(false, |limit| found >= limit) {
+                break;
+            }
+        }
+
+        buffer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Shell sessions: the events of one terminal from a `Start` up to the next
+//! one. Commands of a terminal that never recorded a `Start`, such as
+//! imported ones, form a single session.
+
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+
+use crate::filter::run_time;
+use crate::merge::local_host;
+use crate::{format_duration, Error, Event, Payload, Segment, SuperHist, UnixTime};
+
+struct Session {
+    terminal: String,
+    host: Option<String>,
+    /// Time of the `Start`, or of the oldest event when there is none
+    start: UnixTime,
+    commands: u64,
+    /// Newest first while scanning
+    workdirs: Vec<String>,
+    /// Exit codes by idx, of commands not seen yet
+    exits: HashMap<u64, (u32, UnixTime)>,
+    /// Kept only for the session being replayed, newest first while scanning
+    events: Vec<(Event, Option<(u32, UnixTime)>)>,
+}
+
+impl Session {
+    fn id(&self) -> String {
+        format!("{}:{}", self.start, self.terminal)
+    }
+}
+
+fn format_time(timestamp: UnixTime) -> String {
+    use chrono::prelude::*;
+
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+impl SuperHist {
+    /// Pass each session to `f` as it completes, going back in time. Events
+    /// of the session with `replay` as id, if given, are kept for `f`.
+    fn scan_sessions(&self, host: &Option<String>, terminal: Option<&str>, replay: Option<&str>,
+        mut f: impl FnMut(Session) -> Result<bool, Error>) -> Result<(), Error>
+    {
+        let local_host = local_host()?;
+        let wanted_host = host.as_ref().map(|host| if host == &local_host { None } else { Some(host.clone()) });
+        let mut open : HashMap<(Option<String>, String), Session> = HashMap::new();
+        let mut stop = false;
+
+        let mut lock = Some(self.lock()?);
+
+        for segment in self.segments()? {
+            self.read_segment(&segment, |event| {
+                if terminal.map_or(false, |terminal| terminal != event.terminal) ||
+                    wanted_host.as_ref().map_or(false, |host| host != &event.host)
+                {
+                    return Ok(true);
+                }
+
+                let key = (event.host.clone(), event.terminal.clone());
+                let session = open.entry(key.clone()).or_insert_with(|| Session {
+                    terminal: event.terminal.clone(),
+                    host: event.host.clone(),
+                    start: event.timestamp,
+                    commands: 0,
+                    workdirs: vec![],
+                    exits: HashMap::new(),
+                    events: vec![],
+                });
+                session.start = event.timestamp;
+
+                match &event.payload {
+                    Payload::Start => {
+                        let mut session = open.remove(&key).unwrap();
+                        if replay.map_or(false, |id| id != session.id()) {
+                            session.events.clear();
+                        }
+                        stop = !f(session)?;
+                    }
+                    Payload::Command { .. } => {
+                        session.commands += 1;
+                        let exit = session.exits.remove(&event.idx);
+                        if let Some(workdir) = event.payload.physical_workdir() {
+                            if session.workdirs.last().map(|last| last.as_str()) != Some(workdir) {
+                                session.workdirs.push(workdir.to_owned());
+                            }
+                        }
+                        if replay.is_some() {
+                            session.events.push((event, exit));
+                        }
+                    }
+                    Payload::ExitCode(code) => {
+                        session.exits.insert(event.idx, (*code, event.timestamp));
+                    }
+                    Payload::Unknown => {}
+                }
+
+                Ok(!stop)
+            })?;
+
+            if let Segment::Live = segment {
+                if let Some(lock) = lock.take() {
+                    lock.unlock()?;
+                }
+            }
+
+            if stop {
+                return Ok(());
+            }
+        }
+
+        // What is left began before the history did
+        for (_, mut session) in open.into_iter() {
+            if replay.map_or(false, |id| id != session.id()) {
+                session.events.clear();
+            }
+            if !f(session)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// List the shell sessions, newest first, at most `limit` of them
+    pub(crate) fn sessions(&self, host: &Option<String>, terminal: &Option<String>,
+        limit: Option<usize>) -> Result<(), Error>
+    {
+        let mut sessions = vec![];
+        self.scan_sessions(host, terminal.as_deref(), None, |session| {
+            sessions.push(session);
+            Ok(true)
+        })?;
+        sessions.sort_by(|a, b| b.start.cmp(&a.start).then_with(|| a.terminal.cmp(&b.terminal)));
+
+        let local_host = local_host()?;
+        let mut buffer = BufWriter::new(std::io::stdout());
+        for session in sessions.iter().take(limit.unwrap_or(usize::MAX)) {
+            // In the order they were first visited
+            let mut workdirs : Vec<&str> = vec![];
+            for workdir in session.workdirs.iter().rev() {
+                if !workdirs.contains(&workdir.as_str()) {
+                    workdirs.push(workdir);
+                }
+            }
+            let line = format!("{}  {}  {}  {}  {}", session.id(), format_time(session.start),
+                session.host.as_ref().unwrap_or(&local_host), session.commands, workdirs.join(" "));
+            writeln!(buffer, "{}", line.trim_end())?;
+        }
+        buffer.flush()?;
+
+        Ok(())
+    }
+
+    /// Print the commands of the session with the given id in the order
+    /// they ran, along with their exit codes and run times
+    pub(crate) fn replay_session(&self, host: &Option<String>, id: &str) -> Result<(), Error> {
+        let terminal = match id.split_once(':') {
+            Some((start, terminal)) if start.parse::<UnixTime>().is_ok() => terminal,
+            _ => return Err(Error::InvalidParams),
+        };
+
+        let mut found = None;
+        self.scan_sessions(host, Some(terminal), Some(id), |session| {
+            if session.id() == id {
+                found = Some(session);
+                return Ok(false);
+            }
+            Ok(true)
+        })?;
+        let session = match found {
+            Some(session) => session,
+            None => return Err(Error::SessionNotFound(id.to_owned())),
+        };
+
+        let mut buffer = BufWriter::new(std::io::stdout());
+        let mut workdir = None;
+        for (event, exit) in session.events.iter().rev() {
+            if let Payload::Command { text, .. } = &event.payload {
+                if workdir != event.payload.physical_workdir() {
+                    workdir = event.payload.physical_workdir();
+                    writeln!(buffer, "{}", workdir.unwrap_or(""))?;
+                }
+                let duration = run_time(event, *exit).map(format_duration).unwrap_or_else(|| "-".to_owned());
+                let code = exit.map(|(code, _)| code.to_string()).unwrap_or_else(|| "-".to_owned());
+                writeln!(buffer, "  {}  {:>4}  {:>3}  {}", format_time(event.timestamp), duration, code,
+                    text.replace("\n", "\\n"))?;
+            }
+        }
+        buffer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Summaries of the recorded commands: what runs most, what fails, where
+//! and when.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::filter::{run_time, EventFilter, ExitFilter};
+use crate::{format_duration, Error, Payload, SuperHist};
+
+/// The program a command runs, skipping leading variable assignments
+fn program_of(text: &str) -> Option<&str> {
+    text.split_whitespace().find(|word| {
+        match word.find('=') {
+            Some(pos) => pos == 0 || !word[..pos].chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            None => true,
+        }
+    })
+}
+
+#[derive(Default)]
+struct Tally {
+    count: u64,
+    /// Commands with a known exit status, and how many of those failed
+    exited: u64,
+    failed: u64,
+    /// Commands with a known run time, and their sum
+    timed: u64,
+    total_duration: u64,
+}
+
+impl Tally {
+    fn add(&mut self, exit_code: Option<u32>, duration: Option<u64>) {
+        self.count += 1;
+        if let Some(code) = exit_code {
+            self.exited += 1;
+            if code != 0 {
+                self.failed += 1;
+            }
+        }
+        if let Some(duration) = duration {
+            self.timed += 1;
+            self.total_duration += duration;
+        }
+    }
+
+    fn failure_rate(&self) -> Option<f64> {
+        if self.exited == 0 { None } else { Some(self.failed as f64 / self.exited as f64) }
+    }
+
+    fn average_duration(&self) -> Option<f64> {
+        if self.timed == 0 { None } else { Some(self.total_duration as f64 / self.timed as f64) }
+    }
+}
+
+#[derive(Serialize)]
+struct CountRecord<'a> {
+    name: &'a str,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct ProgramRecord<'a> {
+    name: &'a str,
+    count: u64,
+    failed: u64,
+    failure_rate: Option<f64>,
+    average_duration: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct StatsRecord<'a> {
+    commands: u64,
+    failed: u64,
+    failure_rate: Option<f64>,
+    average_duration: Option<f64>,
+    top_commands: Vec<CountRecord<'a>>,
+    top_programs: Vec<ProgramRecord<'a>>,
+    top_workdirs: Vec<CountRecord<'a>>,
+    /// In local time
+    per_day: &'a BTreeMap<String, u64>,
+    per_hour: &'a [u64; 24],
+}
+
+/// The `limit` names with the highest counts, ties by name
+fn top<'a, T>(counts: &'a HashMap<String, T>, count: impl Fn(&T) -> u64, limit: usize) -> Vec<(&'a str, &'a T)> {
+    let mut sorted : Vec<_> = counts.iter().map(|(name, value)| (name.as_str(), value)).collect();
+    sorted.sort_by(|a, b| count(b.1).cmp(&count(a.1)).then_with(|| a.0.cmp(b.0)));
+    sorted.truncate(limit);
+    sorted
+}
+
+fn percent(rate: Option<f64>) -> String {
+    rate.map(|rate| format!("{:.1}%", rate * 100.0)).unwrap_or_else(|| "-".to_owned())
+}
+
+fn average(duration: Option<f64>) -> String {
+    duration.map(|duration| format_duration(duration.round() as u64)).unwrap_or_else(|| "-".to_owned())
+}
+
+impl SuperHist {
+    /// Print statistics of the commands matching the filters, the `limit`
+    /// most common of each kind
+    pub(crate) fn stats(&self, filter: &EventFilter, exit_filter: &ExitFilter, limit: usize,
+        json: bool) -> Result<(), Error>
+    {
+        use chrono::prelude::*;
+
+        let mut total = Tally::default();
+        let mut commands : HashMap<String, u64> = HashMap::new();
+        let mut programs : HashMap<String, Tally> = HashMap::new();
+        let mut workdirs : HashMap<String, u64> = HashMap::new();
+        let mut per_day = BTreeMap::new();
+        let mut per_hour = [0u64; 24];
+
+        self.scan_commands(None, |index| filter.may_match(index), |event, exit| {
+            if !filter.matches(&event) || !exit_filter.matches(&event, exit) {
+                return Ok(true);
+            }
+            let text = match &event.payload {
+                Payload::Command { text, .. } => text,
+                _ => return Ok(true),
+            };
+
+            let exit_code = exit.map(|(code, _)| code);
+            let duration = run_time(&event, exit);
+            total.add(exit_code, duration);
+            *commands.entry(text.clone()).or_insert(0) += 1;
+            if let Some(program) = program_of(text) {
+                programs.entry(program.to_owned()).or_default().add(exit_code, duration);
+            }
+            if let Some(workdir) = event.payload.physical_workdir() {
+                *workdirs.entry(workdir.to_owned()).or_insert(0) += 1;
+            }
+            if let Some(time) = Local.timestamp_opt(event.timestamp as i64, 0).single() {
+                *per_day.entry(time.format("%Y-%m-%d").to_string()).or_insert(0) += 1;
+                per_hour[time.hour() as usize] += 1;
+            }
+
+            Ok(true)
+        })?;
+
+        let top_commands = top(&commands, |count| *count, limit);
+        let top_programs = top(&programs, |tally| tally.count, limit);
+        let top_workdirs = top(&workdirs, |count| *count, limit);
+
+        let mut buffer = BufWriter::new(std::io::stdout());
+
+        if json {
+            let record = StatsRecord {
+                commands: total.count,
+                failed: total.failed,
+                failure_rate: total.failure_rate(),
+                average_duration: total.average_duration(),
+                top_commands: top_commands.iter()
+                    .map(|(name, count)| CountRecord { name, count: **count }).collect(),
+                top_programs: top_programs.iter()
+                    .map(|(name, tally)| ProgramRecord {
+                        name,
+                        count: tally.count,
+                        failed: tally.failed,
+                        failure_rate: tally.failure_rate(),
+                        average_duration: tally.average_duration(),
+                    }).collect(),
+                top_workdirs: top_workdirs.iter()
+                    .map(|(name, count)| CountRecord { name, count: **count }).collect(),
+                per_day: &per_day,
+                per_hour: &per_hour,
+            };
+            writeln!(buffer, "{}", serde_json::ser::to_string(&record)?)?;
+            buffer.flush()?;
+            return Ok(());
+        }
+
+        writeln!(buffer, "commands          {}", total.count)?;
+        writeln!(buffer, "failed            {} ({})", total.failed, percent(total.failure_rate()))?;
+        writeln!(buffer, "average duration  {}", average(total.average_duration()))?;
+        if !per_day.is_empty() {
+            writeln!(buffer, "active days       {}, {:.1} commands per day", per_day.len(),
+                total.count as f64 / per_day.len() as f64)?;
+        }
+
+        writeln!(buffer, "\ntop commands")?;
+        for (text, count) in top_commands.iter() {
+            writeln!(buffer, "{:8}  {}", count, text.replace("\n", "\\n"))?;
+        }
+
+        writeln!(buffer, "\ntop programs      count  failed  duration")?;
+        for (program, tally) in top_programs.iter() {
+            writeln!(buffer, "  {:16}{:6}  {:>6}  {:>8}", program, tally.count,
+                percent(tally.failure_rate()), average(tally.average_duration()))?;
+        }
+
+        writeln!(buffer, "\nbusiest directories")?;
+        for (workdir, count) in top_workdirs.iter() {
+            writeln!(buffer, "{:8}  {}", count, workdir)?;
+        }
+
+        let days : HashMap<String, u64> = per_day.iter().map(|(day, count)| (day.clone(), *count)).collect();
+        writeln!(buffer, "\nbusiest days")?;
+        for (day, count) in top(&days, |count| *count, limit) {
+            writeln!(buffer, "{:8}  {}", count, day)?;
+        }
+
+        writeln!(buffer, "\ncommands per hour")?;
+        let most = per_hour.iter().copied().max().unwrap_or(0);
+        for (hour, count) in per_hour.iter().enumerate() {
+            let bar = if most == 0 { 0 } else { (count * 40 + most - 1) / most };
+            let line = format!("  {:02}  {:6}  {}", hour, count, "#".repeat(bar as usize));
+            writeln!(buffer, "{}", line.trim_end())?;
+        }
+
+        buffer.flush()?;
+        Ok(())
+    }
+}
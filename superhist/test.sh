@@ -10,6 +10,7 @@ mkdir -p ${tmp_dir}/superhist
 
 ./build.sh --dest ${tmp_dir}/superhist.exe
 bin="${tmp_dir}/superhist.exe --root ${tmp_dir}/superhist"
+now=2000000000
 
 ${bin} add -i 0 -t /dev/pts/10 -x 1600000000 -s
 ${bin} add -i 1 -t /dev/pts/10 -x 1600000001 -c "command 1" -w "/tmp"
@@ -22,32 +23,788 @@ ${bin} add -i 5 -t /dev/pts/10 -x 1600000006 -e 0
 cat ${tmp_dir}/superhist/db.json
 
 check_fc() {
-    ${bin} fc -s 0
+    ${bin} fc -s 0 -t ${now}
 
-    if [[ "$(${bin} fc -s 0 | wc -l)" != "3" ]] ; then
+    if [[ "$(${bin} fc -s 0 -t ${now} | wc -l)" != "3" ]] ; then
 	e=1
     fi
 
-    ${bin} fc -w /tmp/sub -s 0
+    ${bin} fc -w /tmp/sub -s 0 -t ${now}
 
-    if [[ "$(${bin} fc -s 0 -w /tmp/sub | wc -l)" != "2" ]] ; then
+    if [[ "$(${bin} fc -s 0 -t ${now} -w /tmp/sub | wc -l)" != "2" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} fc -s 0 -t ${now} -w /tmp/sub -f 1)" != "command 2" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} fc -s 0 -t 1600000002 | wc -l)" != "1" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} fc -s 0 -t ${now} --max-duration 1s | wc -l)" != "3" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} fc -s 0 -t ${now} --min-duration 2 | wc -l)" != "0" ]] ; then
+	e=1
+    fi
+
+    if ${bin} fc -s 0 -t ${now} --min-duration 999999999999999999d ; then
+	e=1
+    fi
+}
+
+check_search() {
+    if [[ "$(${bin} search "command 2")" != "command 2" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} search -e 'comm[a-z]+d [13]' | wc -l)" != "2" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} search -i -w /tmp/sub COMMAND | wc -l)" != "2" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} search "no such command" | wc -l)" != "0" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} search -e 'comm\x61nd 2')" != "command 2" ]] ||
+	[[ "$(${bin} search -e '(?x) comm and \ 2')" != "command 2" ]] ; then
+	e=1
+    fi
+
+    if [[ "$(${bin} search -n 0 command | wc -l)" != "3" ]] ; then
 	e=1
     fi
 }
 
 check_fc
+check_search
 
 ${bin} archive
 
 ls -l ${tmp_dir}/superhist/archive
 ls -l ${tmp_dir}/superhist
 
+check_fc
+check_search
+
+cat ${tmp_dir}/superhist/archive/*.idx.yaml
+rm ${tmp_dir}/superhist/archive/*.idx.yaml
+${bin} reindex
+
 check_fc
 
 ${bin} add -i 5 -t /dev/pts/10 -x 1600000005 -c "command 4" -w "/tmp/sub"
 ${bin} add -i 5 -t /dev/pts/10 -x 1600000006 -e 0
 
-${bin} fc -s 0
+${bin} fc -s 0 -t ${now}
+
+# An exit code appended after its command was archived, with no command of
+# that workdir left in db.json
+late="${tmp_dir}/superhist.exe --root ${tmp_dir}/late"
+mkdir -p ${tmp_dir}/late
+${late} add -i 6 -t /dev/pts/10 -x 1600000007 -c "command 5" -w "/tmp/late"
+${late} archive
+${late} add -i 7 -t /dev/pts/10 -x 1600000008 -c "command 6" -w "/tmp/other"
+${late} add -i 6 -t /dev/pts/10 -x 1600000009 -e 3
+
+if [[ "$(${late} fc -s 0 -t ${now} -w /tmp/late --failed | wc -l)" != "1" ]] ||
+    [[ "$(${late} fc -s 0 -t ${now} -w /tmp/late --min-duration 2 | wc -l)" != "1" ]] ||
+    [[ "$(${late} fc -s 0 -t ${now} -w /tmp/late --exit-status 3 -f 0)" != "command 5" ]] ; then
+    e=1
+fi
+
+# Block archives spanning several blocks, next to a legacy single-stream archive
+blk="${tmp_dir}/superhist.exe --root ${tmp_dir}/blocks"
+mkdir -p ${tmp_dir}/blocks/archive
+
+for i in $(seq 1 5000) ; do
+    echo ": $((1500000000 + i)) /blk:0;blk command $i"
+done > ${tmp_dir}/blk_history
+${blk} import -p ${tmp_dir}/blk_history
+${blk} archive
+
+for i in $(seq 1 10) ; do
+    echo '{"timestamp":'$((1400000000 + i))',"idx":0,"terminal":"/dev/pts/999","payload":{"Command":{"text":"legacy command '$i'","workdir":"/blk"}}}'
+done | tac | xz > ${tmp_dir}/blocks/archive/2000-01-01-00-00-00-legacy.xz
+
+if [[ "$(${blk} fc -s 0 -t ${now} | wc -l)" != "5010" ]] ; then
+    e=1
+fi
+
+if [[ "$(${blk} fc -s 0 -t ${now} -f 4500)" != "blk command 500" ]] ; then
+    e=1
+fi
+
+if [[ "$(${blk} fc -s 0 -t ${now} -w /blk -f 5003)" != "legacy command 7" ]] ; then
+    e=1
+fi
+
+if [[ "$(${blk} fc -s 0 -t 1500000101 | wc -l)" != "110" ]] ; then
+    e=1
+fi
+
+# Schema migration of v1 records, next to a record from a future version
+echo '{"timestamp":1500009999,"idx":7,"terminal":"/dev/pts/1","payload":{"Command":{"text":"old live command","workdir":"/blk"}}}' >> ${tmp_dir}/blocks/db.json
+echo '{"v":99,"timestamp":1500010000,"idx":8,"terminal":"/dev/pts/1","payload":{"type":"teleport","to":"/"}}' >> ${tmp_dir}/blocks/db.json
+
+if [[ "$(${blk} fc -s 0 -t ${now} | wc -l)" != "5011" ]] ; then
+    e=1
+fi
+
+${blk} migrate
+
+if [[ "$(xz -dc ${tmp_dir}/blocks/archive/2000-01-01-00-00-00-legacy.xz | grep -c '"v":2')" != "10" ]] ; then
+    e=1
+fi
+
+if [[ "$(grep -c '"v":99' ${tmp_dir}/blocks/db.json)" != "1" ]] ; then
+    e=1
+fi
+
+if [[ "$(${blk} fc -s 0 -t ${now} | wc -l)" != "5011" ]] ; then
+    e=1
+fi
+
+if [[ "$(${blk} fc -s 0 -t ${now} -w /blk -f 5004)" != "legacy command 7" ]] ; then
+    e=1
+fi
+
+# Damaged history: a torn last line in db.json and a truncated block archive
+dmg="${tmp_dir}/superhist.exe --root ${tmp_dir}/damaged"
+mkdir -p ${tmp_dir}/damaged/archive
+
+${dmg} add -i 1 -t /dev/pts/1 -x 1600000001 -c "intact 1" -w /d
+${dmg} add -i 2 -t /dev/pts/1 -x 1600000002 -c "intact 2" -w /d
+printf '{"v":2,"timestamp":16000' >> ${tmp_dir}/damaged/db.json
+head -c 3000 ${tmp_dir}/blocks/archive/*.xzb > ${tmp_dir}/damaged/archive/2001-01-01-00-00-00-torn.xzb
+
+if [[ "$(${dmg} fc -s 0 -t ${now} -f 1)" != "intact 1" ]] ; then
+    e=1
+fi
+
+if ${dmg} fsck ; then
+    e=1
+fi
+
+${dmg} fsck --repair
+
+if ! ${dmg} fsck ; then
+    e=1
+fi
+
+if [[ "$(ls ${tmp_dir}/damaged/quarantine | wc -l)" != "2" ]] ; then
+    e=1
+fi
+
+if [[ "$(${dmg} fc -s 0 -t ${now} -f 1)" != "intact 1" ]] ; then
+    e=1
+fi
+
+# Appends cut short are undone as a whole by the next append
+crash="${tmp_dir}/superhist.exe --root ${tmp_dir}/crash"
+mkdir -p ${tmp_dir}/crash
+echo "durability: batch" > ${tmp_dir}/crash/config.yaml
+
+${crash} add -i 1 -t /dev/pts/1 -x 1600000001 -c "before crash" -w /c
+
+# A batch of three records cut short after 200 bytes, with its journal
+for i in 1 2 3 ; do
+    echo '{"v":2,"timestamp":160000000'$((i + 1))',"idx":'${i}',"terminal":"/dev/pts/9","payload":{"type":"command","text":"batch '${i}'","workdir":"/c"}}'
+done > ${tmp_dir}/crash_batch
+echo "$(stat -c %s ${tmp_dir}/crash/db.json) $(stat -c %s ${tmp_dir}/crash_batch)" > ${tmp_dir}/crash/db.json.journal
+head -c 200 ${tmp_dir}/crash_batch >> ${tmp_dir}/crash/db.json
+
+${crash} add -i 2 -t /dev/pts/1 -x 1600000005 -c "after crash" -w /c
+
+if [[ "$(${crash} fc -s 0 -t ${now} | wc -l)" != "2" ]] ; then
+    e=1
+fi
+
+printf '{"v":2,"timest' >> ${tmp_dir}/crash/db.json
+${crash} add -i 3 -t /dev/pts/1 -x 1600000006 -c "after torn record" -w /c
+
+if [[ "$(${crash} fc -s 0 -t ${now} | wc -l)" != "3" ]] ; then
+    e=1
+fi
+
+if ! ${crash} fsck ; then
+    e=1
+fi
+
+# Rotation of db.json into the archive by add
+rot="${tmp_dir}/superhist.exe --root ${tmp_dir}/rotation"
+mkdir -p ${tmp_dir}/rotation
+printf 'rotation:\n  max_events: 4\n' > ${tmp_dir}/rotation/config.yaml
+
+${rot} add -i 0 -t /dev/pts/1 -x 1600000000 -s
+${rot} add -i 1 -t /dev/pts/1 -x 1600000001 -c "rotated 1" -w /r
+${rot} add -i 1 -t /dev/pts/1 -x 1600000002 -e 0
+
+if [[ ! -e ${tmp_dir}/rotation/db.json ]] ; then
+    e=1
+fi
+
+${rot} add -i 2 -t /dev/pts/1 -x 1600000003 -c "rotated 2" -w /r
+
+if [[ -e ${tmp_dir}/rotation/db.json ]] || [[ "$(ls ${tmp_dir}/rotation/archive/*.xzb | wc -l)" != "1" ]] ; then
+    e=1
+fi
+
+if [[ "$(${rot} fc -s 0 -t ${now} | wc -l)" != "2" ]] ; then
+    e=1
+fi
+
+# Purging events from db.json and the archives
+${rot} add -i 3 -t /dev/pts/1 -x 1600000004 -c "login token=abc" -w /r
+${rot} add -i 3 -t /dev/pts/1 -x 1600000005 -e 1
+
+if [[ "$(${rot} purge --regex 'token=' --dry-run | grep -c 'token=abc')" != "1" ]] ; then
+    e=1
+fi
+
+if [[ "$(${rot} fc -s 0 -t ${now} | wc -l)" != "3" ]] ; then
+    e=1
+fi
+
+if ${rot} purge --dry-run ; then
+    e=1
+fi
+
+${rot} purge --regex 'token='
+${rot} purge --glob 'rotated 1'
+
+if grep -q 'token=abc' ${tmp_dir}/rotation/db.json || [[ "$(wc -l < ${tmp_dir}/rotation/db.json)" != "0" ]] ; then
+    e=1
+fi
+
+if [[ "$(${rot} fc -s 0 -t ${now})" != *"rotated 2"* ]] || [[ "$(${rot} fc -s 0 -t ${now} | wc -l)" != "1" ]] ; then
+    e=1
+fi
+
+if [[ "$(${rot} search rotated)" != "rotated 2" ]] ; then
+    e=1
+fi
+
+# Redaction of secrets, when adding and over the existing history
+red="${tmp_dir}/superhist.exe --root ${tmp_dir}/redact"
+mkdir -p ${tmp_dir}/redact
+printf 'redaction:\n  builtin: true\n' > ${tmp_dir}/redact/config.yaml
+
+${red} add -i 1 -t /dev/pts/1 -x 1600000001 -c "export GITHUB_TOKEN=abc123 && make" -w /r
+${red} add -i 4 -t /dev/pts/1 -x 1600000004 -c "TOKENIZER=bert SECRET_FILE=x.txt make" -w /r
+
+if grep -q 'abc123' ${tmp_dir}/redact/db.json || ! grep -q 'GITHUB_TOKEN=\[REDACTED\] && make' ${tmp_dir}/redact/db.json ||
+    ! grep -q 'TOKENIZER=bert SECRET_FILE=x.txt make' ${tmp_dir}/redact/db.json ; then
+    e=1
+fi
+
+printf 'redaction:\n  builtin: false\n' > ${tmp_dir}/redact/config.yaml
+${red} add -i 2 -t /dev/pts/1 -x 1600000002 -c "login hunter2" -w /r
+${red} archive
+${red} add -i 3 -t /dev/pts/1 -x 1600000003 -c "login hunter2 again" -w /r
+
+printf 'redaction:\n  builtin: false\n  patterns: ["login (?P<secret>hunter2)"]\n  placeholder: "***"\n' > ${tmp_dir}/redact/config.yaml
+
+if [[ "$(${red} redact --dry-run | wc -l)" != "2" ]] || [[ "$(${red} search hunter2)" == "" ]] ; then
+    e=1
+fi
+
+${red} redact
+
+if [[ "$(${red} search hunter2)" != "" ]] || [[ "$(${red} search -e 'login \*\*\*' | wc -l)" != "2" ]] ; then
+    e=1
+fi
+
+if [[ "$(${red} redact --dry-run | wc -l)" != "0" ]] ; then
+    e=1
+fi
+
+# Commands that are never written
+ign="${tmp_dir}/superhist.exe --root ${tmp_dir}/ignore"
+mkdir -p ${tmp_dir}/ignore
+printf 'ignore:\n  commands: ["ls", "cd *"]\n  regexes: ["^vault "]\n  workdirs: ["/secret/**"]\n' > ${tmp_dir}/ignore/config.yaml
+
+${ign} add -i 1 -t /dev/pts/1 -x 1600000001 -c "ls" -w /r
+${ign} add -i 2 -t /dev/pts/1 -x 1600000002 -c "cd /etc" -w /r
+${ign} add -i 3 -t /dev/pts/1 -x 1600000003 -c "vault read x" -w /r
+${ign} add -i 4 -t /dev/pts/1 -x 1600000004 -c "make" -w /secret/deep/dir
+${ign} add -i 5 -t /dev/pts/1 -x 1600000005 -c " private" -w /r
+${ign} add -i 6 -t /dev/pts/1 -x 1600000006 -c "ls -l" -w /r
+${ign} add -i 7 -t /dev/pts/1 -x 1600000007 -c "make" -w /secret
+
+if [[ "$(${ign} search -e .)" != "$(printf 'make\nls -l')" ]] ; then
+    e=1
+fi
+
+printf ': 1600000008 /r:0;ls\n: 1600000009 /r:0;git status\n' > ${tmp_dir}/ignore/hist
+out="$(${ign} import -p ${tmp_dir}/ignore/hist)"
+
+if [[ "${out}" != *"imported 1 commands, 0 already present" ]] ||
+    [[ "$(${ign} search -e . | wc -l)" != "3" ]] || [[ "$(${ign} search ls)" != "ls -l" ]] ; then
+    e=1
+fi
+
+# Workdir globs match the physical path too, and the exit codes of ignored
+# commands are not written
+mkdir -p ${tmp_dir}/ignore-real/dir
+ln -s ${tmp_dir}/ignore-real ${tmp_dir}/ignore-link
+printf 'ignore:\n  workdirs: ["%s/**"]\n' "$(realpath ${tmp_dir}/ignore-real)" > ${tmp_dir}/ignore/config.yaml
+
+${ign} add -i 10 -t /dev/pts/2 -x 1600000010 -c "cat key" -w ${tmp_dir}/ignore-link/dir
+${ign} add -i 10 -t /dev/pts/2 -x 1600000011 -e 0
+${ign} add -i 11 -t /dev/pts/2 -x 1600000012 -c "cat notes" -w /r
+${ign} add -i 11 -t /dev/pts/2 -x 1600000013 -e 1
+
+if [[ "$(${ign} search cat)" != "cat notes" ]] || [[ "$(grep -c exit_code ${tmp_dir}/ignore/db.json)" != "1" ]] ||
+    [[ -e ${tmp_dir}/ignore/db.json.ignored ]] ; then
+    e=1
+fi
+
+# Encryption at rest, with plaintext records from before it was enabled
+enc="${tmp_dir}/superhist.exe --root ${tmp_dir}/encrypted"
+mkdir -p ${tmp_dir}/encrypted
+
+${enc} add -i 1 -t /dev/pts/1 -x 1600000001 -c "plain before" -w /enc/dir
+printf 'encryption:\n  key_file: key\n' > ${tmp_dir}/encrypted/config.yaml
+${enc} keygen
+
+if ${enc} keygen ; then
+    e=1
+fi
+
+${enc} add -i 2 -t /dev/pts/1 -x 1600000002 -c "sealed one" -w /enc/dir
+${enc} add -i 2 -t /dev/pts/1 -x 1600000003 -e 0
+printf ': 1600000004 /enc/dir:0;sealed imported\n' > ${tmp_dir}/encrypted.hist
+${enc} import -p ${tmp_dir}/encrypted.hist
+
+if grep -q 'sealed' ${tmp_dir}/encrypted/db.json || ! grep -q 'plain before' ${tmp_dir}/encrypted/db.json ; then
+    e=1
+fi
+
+if [[ "$(${enc} fc -w /enc/dir -s 0 -t ${now} | wc -l)" != "3" ]] ; then
+    e=1
+fi
+
+${enc} archive
+${enc} add -i 3 -t /dev/pts/1 -x 1600000005 -c "sealed after archive" -w /enc/dir
+
+if [[ "$(${enc} search sealed | wc -l)" != "3" ]] || [[ "$(${enc} fc -w /enc/dir -s 0 -t ${now} | wc -l)" != "4" ]] ; then
+    e=1
+fi
+
+if cat ${tmp_dir}/encrypted/archive/* ${tmp_dir}/encrypted/db.idx.yaml | grep -q '/enc/dir' ; then
+    e=1
+fi
+
+if ! ${enc} fsck ; then
+    e=1
+fi
+
+mv ${tmp_dir}/encrypted/config.yaml ${tmp_dir}/encrypted/config.yaml.off
+
+if ${enc} fc -w /enc/dir -s 0 -t ${now} ; then
+    e=1
+fi
+
+mv ${tmp_dir}/encrypted/config.yaml.off ${tmp_dir}/encrypted/config.yaml
+
+# Merging the history of another machine
+m1="${tmp_dir}/superhist.exe --root ${tmp_dir}/merge1"
+m2="${tmp_dir}/superhist.exe --root ${tmp_dir}/merge2"
+mkdir -p ${tmp_dir}/merge1 ${tmp_dir}/merge2
+
+${m1} add -i 1 -t /dev/pts/1 -x 1600000010 -c "local one" -w /m
+${m1} add -i 1 -t /dev/pts/1 -x 1600000010 -e 0
+${m1} archive
+${m1} add -i 2 -t /dev/pts/1 -x 1600000030 -c "local two" -w /m
+${m2} add -i 1 -t /dev/pts/1 -x 1600000020 -c "remote one" -w /m
+${m2} archive
+${m2} add -i 2 -t /dev/pts/1 -x 1600000040 -c "remote two" -w /m
+echo '{"v":99,"timestamp":1600000041,"idx":2,"terminal":"/dev/pts/1","payload":{"type":"teleport","to":"/"},"future":"kept"}' >> ${tmp_dir}/merge2/db.json
+
+mkdir -p ${tmp_dir}/merge3
+${tmp_dir}/superhist.exe --root ${tmp_dir}/merge3 add -i 1 -t /dev/pts/1 -x 1600000001 -c "unknown host" -w /m
+
+if ${m1} merge ${tmp_dir}/merge3 ; then
+    e=1
+fi
+
+${m1} merge --host remote ${tmp_dir}/merge2
+
+# Our segments from the time of the new events are rewritten along with them,
+# so that fc orders them all by time
+if [[ "$(${m1} fc -s 0 -t ${now} | grep -o '[a-z]* [a-z]*$' | tr '\n' ,)" != "remote two,local two,remote one,local one," ]] ||
+    [[ -e ${tmp_dir}/merge1/db.json ]] ; then
+    e=1
+fi
+
+# Records of a newer version are taken in whole
+merged="$(for archive in ${tmp_dir}/merge1/archive/*.xzb ; do xz -dc --single-stream ${archive} ; done)"
+if [[ "${merged}" != *'"future":"kept"'* ]] || [[ "${merged}" != *'"type":"teleport"'* ]] ; then
+    e=1
+fi
+
+if [[ "$(${m1} merge --host remote ${tmp_dir}/merge2)" != *"nothing new"* ]] ; then
+    e=1
+fi
+
+# Merging back the other way takes only our own events, and the host they are
+# merged under sticks to our root
+if [[ "$(${m2} merge --host local ${tmp_dir}/merge1)" != *"merged 3 events"* ]] ||
+    [[ "$(cat ${tmp_dir}/merge1/host)" != "local" ]] || ${m1} merge --host other ${tmp_dir}/merge2 ; then
+    e=1
+fi
+
+${m1} add -i 3 -t /dev/pts/1 -x 1600000050 -c "local three" -w /m
+${m2} add -i 3 -t /dev/pts/1 -x 1600000045 -c "remote three" -w /m
+
+if [[ "$(${m1} merge ${tmp_dir}/merge2)" != *"merged 1 events"* ]] ; then
+    e=1
+fi
+
+if [[ "$(${m1} fc -s 0 -t ${now} | grep -o '[a-z]* [a-z]*$' | tr '\n' ,)" != "local three,remote three,remote two,local two,remote one,local one," ]] ||
+    [[ "$(${m1} export --format jsonl | wc -l)" != "6" ]] || [[ "$(${m2} export --format jsonl | wc -l)" != "5" ]] ||
+    ! ${m1} fsck ; then
+    e=1
+fi
+
+# Export to other formats
+exp="${tmp_dir}/superhist.exe --root ${tmp_dir}/export"
+mkdir -p ${tmp_dir}/export
+
+${exp} add -i 1 -t /dev/pts/1 -x 1600000001 -c "make all" -w /x
+${exp} add -i 1 -t /dev/pts/1 -x 1600000011 -e 0
+${exp} archive
+${exp} add -i 2 -t /dev/pts/1 -x 1600000020 -c 'echo "a,b"' -w /y
+${exp} add -i 2 -t /dev/pts/1 -x 1600000021 -e 2
+${exp} add -i 3 -t /dev/pts/1 -x 1600000030 -c "$(printf 'for x in 1 2\ndo echo $x\ndone')" -w /x
+
+if [[ "$(${exp} export --format zsh)" != "$(printf ': 1600000001:10;make all\n: 1600000020:1;echo "a,b"\n: 1600000030:0;for x in 1 2\\\ndo echo $x\\\ndone')" ]] ; then
+    e=1
+fi
+
+if [[ "$(${exp} export --format bash -w /x)" != "$(printf '#1600000001\nmake all\n#1600000030\nfor x in 1 2\ndo echo $x\ndone')" ]] ; then
+    e=1
+fi
+
+if [[ "$(${exp} export --format fish --since 1600000010 --until 1600000025)" != "$(printf -- '- cmd: echo "a,b"\n  when: 1600000020')" ]] ; then
+    e=1
+fi
+
+if [[ "$(${exp} export --failed)" != '{"timestamp":1600000020,"command":"echo \"a,b\"","workdir":"/y","terminal":"/dev/pts/1","host":null,"exit_code":2,"duration":1}' ]] ; then
+    e=1
+fi
+
+if [[ "$(${exp} export --format bash --min-duration 5)" != "$(printf '#1600000001\nmake all')" ]] ||
+    [[ "$(${exp} export --format zsh --max-duration 1 | wc -l)" != "1" ]] ; then
+    e=1
+fi
+
+${exp} export --format csv --exit-status 0 -o ${tmp_dir}/export.csv
+
+if [[ "$(cat ${tmp_dir}/export.csv)" != "$(printf 'timestamp,workdir,terminal,host,exit_code,duration,command\n1600000001,/x,/dev/pts/1,,0,10,make all')" ]] ; then
+    e=1
+fi
+
+if ${exp} export --format nope ; then
+    e=1
+fi
+
+# Importing the histories of other shells and tools
+imp="${tmp_dir}/superhist.exe --root ${tmp_dir}/import"
+mkdir -p ${tmp_dir}/import
+
+printf '#1600000001\nbash timed\n#1600000002\nbash timed two\n' > ${tmp_dir}/import.bash
+printf 'bash plain\nbash plain two\n' > ${tmp_dir}/import.plain
+printf -- '- cmd: fish one\n  when: 1600000003\n  paths:\n    - /x\n- cmd: fish \\\\ two\\nlines\n  when: 1600000004\n' > ${tmp_dir}/import.fish
+printf ': 1600000005:3;zsh stock\\\nsecond line\n' > ${tmp_dir}/import.zsh
+printf '{"timestamp":1600000006,"command":"json one","workdir":"/j"}\n{"cmd":"json two","when":1600000007}\n' > ${tmp_dir}/import.jsonl
+
+for f in bash plain fish zsh jsonl ; do
+    ${imp} import -p ${tmp_dir}/import.${f}
+done
+
+expected="$(printf 'bash timed\nbash timed two\nbash plain\nbash plain two\nfish one\nfish \\\\ two\\nlines\nzsh stock\\nsecond line\njson one\njson two')"
+if [[ "$(${imp} export --format jsonl | sed 's/.*"command":"\([^"]*\)".*/\1/')" != "${expected}" ]] ; then
+    e=1
+fi
+
+if [[ "$(${imp} export --format csv -w /j | tail -1)" != "1600000006,/j,import:$(realpath ${tmp_dir}/import.jsonl),,,,json one" ]] ; then
+    e=1
+fi
+
+printf 'not zsh\n' > ${tmp_dir}/import.forced
+${imp} import --format zsh -p ${tmp_dir}/import.forced
+
+if ${imp} search "not zsh" | grep -q . || ${imp} import --format nope -p ${tmp_dir}/import.forced ; then
+    e=1
+fi
+
+# Stock zsh records how long commands ran, which the fork records where
+printf ': 1600000010:7;make slow\n: 1600000011 /src:0;make fast\n: 1600000012:0;make: all\n' > ${tmp_dir}/import.mixed
+${imp} import -p ${tmp_dir}/import.mixed
+
+expected="$(printf '1600000010,,,7,make slow\n1600000011,/src,,,make fast\n1600000012,,,0,make: all')"
+if [[ "$(${imp} export --format csv --since 1600000010 --until 1600000020 | tail -n +2 | cut -d, -f1,2,5-)" != "${expected}" ]] ; then
+    e=1
+fi
+
+if [[ "$(${imp} fc -s 0 -t ${now} --min-duration 5)" != *"make slow" ]] ; then
+    e=1
+fi
+
+# zsh history files hold non-ASCII text metafied, and may hold bytes that are
+# not UTF-8 at all
+meta="${tmp_dir}/superhist.exe --root ${tmp_dir}/meta"
+mkdir -p ${tmp_dir}/meta
+
+# "→" is e2 86 92 and "日" is e6 97 a5, with 0x86, 0x92 and 0x97 metafied
+printf ': 1600000001:0;echo \xe2\x83\xa6\x83\xb2 caf\xc3\xa9\n: 1600000002:0;echo \xe6\x83\xb7\xa5\\\n\xe6\x83\xb7\xa5\n' > ${tmp_dir}/meta.zsh
+printf ': 1600000003 /m:0;cd \xe2\x83\xa6\x83\xb2\n' > ${tmp_dir}/meta.perdir
+printf '#1600000004\nbad \xff byte\n#1600000005\nfine\n' > ${tmp_dir}/meta.bash
+
+${meta} import -p ${tmp_dir}/meta.zsh
+${meta} import -p ${tmp_dir}/meta.perdir
+warning="$(${meta} import -p ${tmp_dir}/meta.bash 2>&1 >/dev/null)"
+
+if [[ "${warning}" != *"1 lines are not valid UTF-8"* ]] ; then
+    e=1
+fi
+
+expected="$(printf 'echo → café\necho 日\n日\ncd →\nbad \xef\xbf\xbd byte\nfine')"
+if [[ "$(${meta} export --format bash | grep -v '^#')" != "${expected}" ]] ; then
+    e=1
+fi
+
+if [[ "$(${meta} export --format csv -w /m | tail -1)" != *",cd →" ]] ; then
+    e=1
+fi
+
+# Importing again, or a file overlapping one already imported, skips what is
+# already there, repeats included
+reimp="${tmp_dir}/superhist.exe --root ${tmp_dir}/reimport"
+mkdir -p ${tmp_dir}/reimport
+
+printf '#1600000001\nls\n#1600000001\nls\n#1600000002\nmake\n' > ${tmp_dir}/reimport.bash
+${reimp} import -p ${tmp_dir}/reimport.bash
+${reimp} import -p ${tmp_dir}/reimport.bash
+
+if [[ "$(${reimp} export --format bash | grep -c -v '^#')" != "3" ]] ; then
+    e=1
+fi
+
+printf '#1600000002\nmake\n#1600000003\nmake install\n' > ${tmp_dir}/reimport.more
+out="$(${reimp} import --source $(realpath ${tmp_dir}/reimport.bash) -p ${tmp_dir}/reimport.more)"
+if [[ "${out}" != *"imported 1 commands, 1 already present" ]] ; then
+    e=1
+fi
+
+${reimp} import --source other -p ${tmp_dir}/reimport.more
+if [[ "$(${reimp} export --format csv | grep -c ',import:other,')" != "2" ]] ; then
+    e=1
+fi
+
+# Commands imported before sources were recorded are skipped too
+legacy="${tmp_dir}/superhist.exe --root ${tmp_dir}/legacy-import"
+mkdir -p ${tmp_dir}/legacy-import
+for record in 1600000001:ls 1600000001:ls 1600000002:make ; do
+    echo '{"v":2,"timestamp":'${record%:*}',"idx":0,"terminal":"/dev/pts/999","payload":{"type":"command","text":"'${record#*:}'","workdir":""}}'
+done > ${tmp_dir}/legacy-import/db.json
+
+out="$(${legacy} import -p ${tmp_dir}/reimport.bash)"
+if [[ "${out}" != *"imported 0 commands, 3 already present" ]] ; then
+    e=1
+fi
+out="$(${legacy} import -p ${tmp_dir}/reimport.more)"
+if [[ "${out}" != *"imported 1 commands, 1 already present" ]] ; then
+    e=1
+fi
+
+# Filtering fc, which keeps numbering commands filtered out
+fcq="${tmp_dir}/superhist.exe --root ${tmp_dir}/fcq"
+mkdir -p ${tmp_dir}/fcq
+
+${fcq} add -i 1 -t /dev/pts/1 -x 1600000001 -c "make build" -w /p
+${fcq} add -i 1 -t /dev/pts/1 -x 1600000003 -e 0
+${fcq} add -i 2 -t /dev/pts/1 -x 1600000010 -c "make test" -w /p
+${fcq} add -i 2 -t /dev/pts/1 -x 1600000011 -e 2
+${fcq} add -i 1 -t /dev/pts/2 -x 1600000020 -c "git push" -w /q
+${fcq} add -i 1 -t /dev/pts/2 -x 1600000030 -e 1
+${fcq} add -i 3 -t /dev/pts/1 -x 1600000040 -c "ls" -w /p
+
+for check in "--failed:2" "--succeeded:1" "--exit-status 2:1" "--terminal /dev/pts/2:1" \
+    "--since 1600000010 --until 1600000040:2" "--regex ^make --failed:1" "--min-duration 5:1" \
+    "-w /p --failed:1" "--host $(hostname):4" "--host elsewhere:0" ; do
+    if [[ "$(${fcq} fc -s 0 -t ${now} ${check%:*} | wc -l)" != "${check##*:}" ]] ; then
+        e=1
+    fi
+done
+
+if [[ "$(${fcq} fc --failed -s 0 -t ${now} -f 2)" != "make test" ]] ||
+    [[ "$(${fcq} fc --failed -s 0 -t ${now} -f 0)" != "" ]] ; then
+    e=1
+fi
+
+stats="$(TZ=UTC ${fcq} stats --json)"
+if [[ "${stats}" != '{"commands":4,"failed":2,'* ]] ||
+    [[ "${stats}" != *'"top_programs":[{"name":"make","count":2,"failed":1,"failure_rate":0.5,"average_duration":1.5}'* ]] ||
+    [[ "${stats}" != *'"per_day":{"2020-09-13":4}'* ]] ||
+    [[ "$(${fcq} stats -w /p | head -n 1)" != "commands          3" ]] ; then
+    e=1
+fi
+
+# Scoping per-directory history to a subtree or a git repository
+scope="${tmp_dir}/superhist.exe --root ${tmp_dir}/scope"
+mkdir -p ${tmp_dir}/scope
+repo="$(realpath ${tmp_dir})/repo"
+
+git init -q ${repo}
+git -C ${repo} -c user.name=test -c user.email=test@example.com commit -q --allow-empty -m init
+git -C ${repo} worktree add -q ${repo}-wt
+mkdir -p ${repo}/src
+
+${scope} add -i 1 -t /dev/pts/1 -x 1600000001 -c "at root" -w ${repo}
+${scope} add -i 2 -t /dev/pts/1 -x 1600000002 -c "in src" -w ${repo}/src
+${scope} add -i 3 -t /dev/pts/1 -x 1600000003 -c "in sibling" -w ${repo}2
+${scope} archive
+${scope} add -i 4 -t /dev/pts/1 -x 1600000004 -c "in worktree" -w ${repo}-wt/lib
+${scope} add -i 5 -t /dev/pts/1 -x 1600000005 -c "elsewhere" -w /other
+
+for check in "exact:1" "subtree:2" "repo:3" ; do
+    if [[ "$(${scope} fc -s 0 -t ${now} -w ${repo} --scope ${check%:*} | wc -l)" != "${check##*:}" ]] ; then
+        e=1
+    fi
+done
+
+fetched="$(for nr in 0 1 2 ; do ${scope} fc -s 0 -t ${now} -w ${repo}/src --scope repo -f ${nr} ; done)"
+if [[ "$(echo ${fetched})" != "in worktree in src at root" ]] ; then
+    e=1
+fi
+
+if [[ "$(${scope} export -w /nonrepo --scope repo | wc -l)" != "0" ]] ||
+    [[ "$(${scope} export -w ${repo}-wt --scope repo | wc -l)" != "3" ]] ; then
+    e=1
+fi
+
+# Ranking by frecency, as of the time given to fc
+rank="${tmp_dir}/superhist.exe --root ${tmp_dir}/rank"
+mkdir -p ${tmp_dir}/rank
+
+for i in 1 2 3 4 5 6 7 8 ; do
+    ${rank} add -i ${i} -t /dev/pts/1 -x $((1698790400 + i * 10)) -c "make" -w /m
+    ${rank} add -i ${i} -t /dev/pts/1 -x $((1698790400 + i * 10 + 1)) -e 0
+done
+for i in 1 2 3 4 ; do
+    ${rank} add -i $((10 + i)) -t /dev/pts/1 -x $((1698790500 + i * 10)) -c "flaky" -w /r
+    ${rank} add -i $((10 + i)) -t /dev/pts/1 -x $((1698790500 + i * 10 + 1)) -e 1
+done
+${rank} add -i 20 -t /dev/pts/1 -x 1699996400 -c "rare" -w /m
+
+ranked() {
+    for nr in 0 1 2 ; do
+        ${rank} fc -s 0 -t 1700000000 -f ${nr} "$@"
+    done
+}
+
+if [[ "$(${rank} fc -s 0 -t 1700000000 -f 0)" != "rare" ]] ||
+    [[ "$(ranked --rank frecency | tr '\n' ' ')" != "make rare flaky " ]] ||
+    [[ "$(ranked --rank frecency --rank-workdir /r | tr '\n' ' ')" != "make flaky rare " ]] ||
+    [[ "$(${rank} fc -s 0 -t 1700000000 --rank frecency | wc -l)" != "3" ]] ; then
+    e=1
+fi
+
+# Candidates of the picker: each command once, those of the workdir unless
+# global, best fuzzy match first, then newest or by frecency
+pick="${tmp_dir}/superhist.exe --root ${tmp_dir}/pick"
+mkdir -p ${tmp_dir}/pick
+base=$(($(date +%s) - 100))
+
+${pick} add -i 1 -t /dev/pts/1 -x $((base + 1)) -c "make build" -w /a
+${pick} add -i 2 -t /dev/pts/1 -x $((base + 2)) -c "git status" -w /a
+${pick} add -i 3 -t /dev/pts/1 -x $((base + 3)) -c "make test" -w /b
+${pick} add -i 3 -t /dev/pts/1 -x $((base + 4)) -e 2
+${pick} add -i 4 -t /dev/pts/1 -x $((base + 5)) -c "make build" -w /a
+${pick} add -i 5 -t /dev/pts/1 -x $((base + 6)) -c "cargo run" -w /b
+
+candidates() {
+    ${pick} pick --print-candidates "$@" | tr '\n' ,
+}
+
+if [[ "$(candidates -w /a)" != "make build,git status," ]] ||
+    [[ "$(candidates -w /a --global)" != "cargo run,make build,make test,git status," ]] ||
+    [[ "$(candidates --global -q mk)" != "make build,make test," ]] ||
+    [[ "$(candidates --global -q st)" != "git status,make test," ]] ||
+    [[ "$(candidates --global -q 'MK')" != "" ]] ||
+    [[ "$(candidates -w /b --failed)" != "make test," ]] ||
+    [[ "$(candidates --global --rank frecency)" != "make build,cargo run,git status,make test," ]] ||
+    [[ "$(candidates -w /b --global --rank frecency)" != "cargo run,make test,make build,git status," ]] ; then
+    e=1
+fi
+
+# Workdirs entered through a symlink match their physical path
+canon="${tmp_dir}/superhist.exe --root ${tmp_dir}/canon"
+mkdir -p ${tmp_dir}/canon ${tmp_dir}/physical
+ln -s ${tmp_dir}/physical ${tmp_dir}/logical
+physical="$(realpath ${tmp_dir}/physical)"
+
+echo '{"timestamp":1600000001,"idx":1,"terminal":"/dev/pts/1","payload":{"Command":{"text":"old via link","workdir":"'${tmp_dir}'/logical"}}}' >> ${tmp_dir}/canon/db.json
+${canon} add -i 2 -t /dev/pts/1 -x 1600000002 -c "new via link" -w ${tmp_dir}/logical
+${canon} add -i 3 -t /dev/pts/1 -x 1600000003 -c "new direct" -w ${physical}
+
+if [[ "$(${canon} fc -s 0 -t ${now} -w ${physical} | wc -l)" != "2" ]] ||
+    [[ "$(${canon} fc -s 0 -t ${now} -w ${tmp_dir}/logical -f 1)" != "new via link" ]] ; then
+    e=1
+fi
+
+if [[ "$(${canon} recanonicalize -n | wc -l)" != "1" ]] ; then
+    e=1
+fi
+${canon} recanonicalize
+${canon} archive
+if [[ "$(${canon} fc -s 0 -t ${now} -w ${physical} | wc -l)" != "3" ]] ||
+    [[ "$(${canon} recanonicalize -n | wc -l)" != "0" ]] ||
+    [[ "$(${canon} search via -w ${tmp_dir}/logical | wc -l)" != "2" ]] ; then
+    e=1
+fi
+
+# Sessions per terminal, across archived and live history
+session="${tmp_dir}/superhist.exe --root ${tmp_dir}/session"
+mkdir -p ${tmp_dir}/session
+
+${session} add -i 0 -t /dev/pts/1 -x 1600000000 -s
+${session} add -i 1 -t /dev/pts/1 -x 1600000001 -c "make" -w /a
+${session} add -i 1 -t /dev/pts/1 -x 1600000005 -e 0
+${session} add -i 0 -t /dev/pts/2 -x 1600000002 -s
+${session} add -i 1 -t /dev/pts/2 -x 1600000003 -c "ls" -w /b
+${session} archive
+${session} add -i 2 -t /dev/pts/1 -x 1600000010 -c "make test" -w /a/t
+${session} add -i 2 -t /dev/pts/1 -x 1600000011 -e 2
+${session} add -i 0 -t /dev/pts/1 -x 1600001000 -s
+${session} add -i 1 -t /dev/pts/1 -x 1600001001 -c "vim" -w /c
+
+if [[ "$(${session} session | cut -d ' ' -f 1 | tr '\n' ' ')" != "1600001000:/dev/pts/1 1600000002:/dev/pts/2 1600000000:/dev/pts/1 " ]] ||
+    [[ "$(${session} session --terminal /dev/pts/1 -n 1 | wc -l)" != "1" ]] ||
+    [[ "$(${session} session | tail -n 1)" != *"  2  /a /a/t" ]] ; then
+    e=1
+fi
+
+replay="$(TZ=UTC ${session} session 1600000000:/dev/pts/1)"
+if [[ "${replay}" != "/a
+  2020-09-13 12:26:41    4s    0  make
+/a/t
+  2020-09-13 12:26:50    1s    2  make test" ]] ; then
+    e=1
+fi
+if ${session} session 1600000001:/dev/pts/1 ; then
+    e=1
+fi
 
 ${bin} proc-add -a "procedure" -c "command" -w "/w"
 ${bin} proc-add -a "other-procedure" -c "other-command" -w "/w"
@@ -354,6 +354,18 @@ if [[ -e ${ZSH_ROOT}/superhist/bin/superhist ]] ; then
 	echo $(dirname ${HISTFILE})/superhist
     }
 
+    # Extra `superhist fc` filters for the history widgets, e.g.
+    # SUPERHIST_FC_FILTER=(--succeeded --rank frecency)
+    typeset -ga SUPERHIST_FC_FILTER
+
+    # What the per-directory history covers: exact, subtree, or repo for all
+    # worktrees of the enclosing git repository
+    : ${SUPERHIST_SCOPE:=exact}
+
+    # Set to 1 to have the history widgets use the picker of superhist rather
+    # than fzf
+    : ${SUPERHIST_PICKER:=0}
+
     _superhist_proc_res=
     _superhist=true
     _superhist_idx=0
@@ -393,20 +405,20 @@ if [[ -e ${ZSH_ROOT}/superhist/bin/superhist ]] ; then
     function _fc_per_directory_history() {
 	local SUPERHIST_ROOT=$(_superhist_root)
 	local start_time="${1}"
-	${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT} fc -s 1 -w $(realpath $PWD) -t ${start_time}
+	${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT} fc ${SUPERHIST_FC_FILTER[@]} -s 1 -w $(realpath $PWD) --scope ${SUPERHIST_SCOPE} -t ${start_time}
     }
 
     function _fc_history() {
 	local SUPERHIST_ROOT=$(_superhist_root)
 	local start_time="${1}"
-	${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT} fc -s 1 -t ${start_time}
+	${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT} fc ${SUPERHIST_FC_FILTER[@]} -s 1 -t ${start_time}
     }
 
     function _fc_per_directory_history_fetch() {
 	local SUPERHIST_ROOT=$(_superhist_root)
 	local item="${1}"
 	local start_time="${2}"
-	BUFFER=$(${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT}  fc -s 1 -w $(realpath $PWD) -f ${item} -t ${start_time})
+	BUFFER=$(${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT}  fc ${SUPERHIST_FC_FILTER[@]} -s 1 -w $(realpath $PWD) --scope ${SUPERHIST_SCOPE} -f ${item} -t ${start_time})
 	zle end-of-buffer-or-history
     }
 
@@ -414,10 +426,25 @@ if [[ -e ${ZSH_ROOT}/superhist/bin/superhist ]] ; then
 	local SUPERHIST_ROOT=$(_superhist_root)
 	local item="${1}"
 	local start_time="${2}"
-	BUFFER=$(${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT}  fc -s 1 -f ${item} -t ${start_time})
+	BUFFER=$(${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT}  fc ${SUPERHIST_FC_FILTER[@]} -s 1 -f ${item} -t ${start_time})
 	zle end-of-buffer-or-history
     }
 
+    function superhist-pick-widget() {
+	local SUPERHIST_ROOT=$(_superhist_root)
+	local selected
+	selected=$(${ZSH_ROOT}/superhist/bin/superhist --root ${SUPERHIST_ROOT} pick ${SUPERHIST_FC_FILTER[@]} -w $(realpath $PWD) --scope ${SUPERHIST_SCOPE} -q "${LBUFFER}" "$@")
+	if [[ -n "${selected}" ]] ; then
+	    BUFFER=${selected}
+	    zle end-of-buffer-or-history
+	fi
+	zle reset-prompt
+    }
+
+    function superhist-pick-global-widget() {
+	superhist-pick-widget --global
+    }
+
     autoload -U add-zsh-hook
     add-zsh-hook zshaddhistory _superhist-addhistory
     add-zsh-hook precmd _superhist-precmd
@@ -467,6 +494,17 @@ fzf-super-history-widget() {
 zle     -N   fzf-super-history-widget
 bindkey '^R' fzf-super-history-widget
 
+# The picker of superhist replaces fzf for both if asked to, and switches
+# between them with CTRL-R
+if [[ -n "${_superhist}" ]] && [[ "${SUPERHIST_PICKER}" == 1 ]] ; then
+    zle -N superhist-pick-widget
+    zle -N superhist-pick-global-widget
+    bindkey '^Ne' superhist-pick-widget
+    bindkey '^Nh' superhist-pick-widget
+    bindkey '^N^H' superhist-pick-widget
+    bindkey '^R' superhist-pick-global-widget
+fi
+
 up-line-or-local-history() {
     zle set-local-history 1
     zle up-line-or-history